@@ -1,61 +1,1305 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
 /// An operation to perform on two subexpressions.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Operation {
     Add,
     Sub,
     Mul,
     Div,
+    Rem,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Logical and, which only evaluates its right operand if the left is true.
+    And,
+    /// Logical or, which only evaluates its right operand if the left is false.
+    Or,
+}
+
+/// An operation to perform on one subexpression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnaryOp {
+    Neg,
+    /// Logical not of a boolean, or bitwise not of an integer.
+    Not,
+}
+
+impl Operation {
+    /// The operator as it is written in an expression.
+    fn symbol(&self) -> &'static str {
+        match self {
+            Operation::Add => "+",
+            Operation::Sub => "-",
+            Operation::Mul => "*",
+            Operation::Div => "/",
+            Operation::Rem => "%",
+            Operation::Pow => "**",
+            Operation::BitAnd => "&",
+            Operation::BitOr => "|",
+            Operation::BitXor => "^",
+            Operation::Shl => "<<",
+            Operation::Shr => ">>",
+            Operation::Eq => "==",
+            Operation::Ne => "!=",
+            Operation::Lt => "<",
+            Operation::Le => "<=",
+            Operation::Gt => ">",
+            Operation::Ge => ">=",
+            Operation::And => "&&",
+            Operation::Or => "||",
+        }
+    }
+}
+
+impl UnaryOp {
+    /// The operator as it is written in an expression.
+    fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+        }
+    }
 }
 
 /// An expression, in tree form.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Expression {
     /// An operation on two subexpressions.
     Op { op: Operation, left: Box<Expression>, right: Box<Expression> },
 
+    /// An operation on one subexpression.
+    Unary { op: UnaryOp, operand: Box<Expression> },
+
+    /// Evaluate `then` if `cond` is true, and `else_` otherwise.
+    If { cond: Box<Expression>, then: Box<Expression>, else_: Box<Expression> },
+
     /// A literal value
-    Value(i64),
+    Value(Value),
+
+    /// A variable, looked up in the environment or an enclosing `Let`.
+    Var(String),
+
+    /// Evaluate `body` with `name` bound to the value of `value`.
+    Let { name: String, value: Box<Expression>, body: Box<Expression> },
+}
+
+/// A step from an expression to one of its subexpressions. A list of steps
+/// from the root identifies a node, such as the one an `EvalError` is about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    /// The left operand of an `Op`.
+    Left,
+    /// The right operand of an `Op`.
+    Right,
+    /// The bound value of a `Let`.
+    Value,
+    /// The body of a `Let`.
+    Body,
+    /// The operand of a `Unary`.
+    Operand,
+    /// The condition of an `If`.
+    Cond,
+    /// The branch of an `If` taken when the condition is true.
+    Then,
+    /// The branch of an `If` taken when the condition is false.
+    Else,
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::Left => "left",
+            Step::Right => "right",
+            Step::Value => "value",
+            Step::Body => "body",
+            Step::Operand => "operand",
+            Step::Cond => "cond",
+            Step::Then => "then",
+            Step::Else => "else",
+        }
+    }
+}
+
+impl Expression {
+    /// The subexpression reached by following `path` from this one.
+    fn at(&self, path: &[Step]) -> Option<&Expression> {
+        let mut e = self;
+        for step in path {
+            e = match (e, step) {
+                (Expression::Op { left, .. }, Step::Left) => left,
+                (Expression::Op { right, .. }, Step::Right) => right,
+                (Expression::Let { value, .. }, Step::Value) => value,
+                (Expression::Let { body, .. }, Step::Body) => body,
+                (Expression::Unary { operand, .. }, Step::Operand) => operand,
+                (Expression::If { cond, .. }, Step::Cond) => cond,
+                (Expression::If { then, .. }, Step::Then) => then,
+                (Expression::If { else_, .. }, Step::Else) => else_,
+                _ => return None,
+            };
+        }
+        Some(e)
+    }
+}
+
+/// A value an expression evaluates to.
+///
+/// An integer combined with a float is promoted to a float, and compared with
+/// one as a float. Other operations need operands of the same type: strings
+/// can be compared and joined with `+`, and booleans compared and combined
+/// with the logical and bitwise operators. Floats follow IEEE 754, so dividing
+/// one by zero gives an infinity or NaN rather than an error.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+/// The type of a `Value`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Float,
+    Bool,
+    Str,
+}
+
+impl Value {
+    fn type_of(&self) -> Type {
+        match self {
+            Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::Bool(_) => Type::Bool,
+            Value::Str(_) => Type::Str,
+        }
+    }
+
+    /// The value as a float, if it is a number.
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(x) => Some(x as f64),
+            Value::Float(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(x) => write!(f, "{x}"),
+            // Debug formatting keeps the `.0` of whole numbers.
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Str(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Bool => "bool",
+            Type::Str => "str",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// Dropping a deep tree recursively would overflow the stack, so children are
+// moved out onto a list and dropped one at a time.
+impl Drop for Expression {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        take_children(self, &mut pending);
+        while let Some(mut e) = pending.pop() {
+            take_children(&mut e, &mut pending);
+        }
+    }
 }
 
-fn eval(e: Expression) -> Result<i64, String> {
-    
+/// Move the children of `e` onto `out`, leaving leaves in their place.
+fn take_children(e: &mut Expression, out: &mut Vec<Expression>) {
+    let mut take = |child: &mut Box<Expression>| {
+        out.push(std::mem::replace(&mut **child, Expression::Value(Value::Bool(false))));
+    };
     match e {
-      // Base case
-      Expression::Value(x) => Ok(x),
-      
-      // Recursive case
-      Expression::Op{op, left, right} => {
-          // Evaluate left and right expressions and return if any errors
-          let left_value = match eval(*left) {
-              Ok(x) => x,
-              err @ Err(_) => return err
-          };
-          
-          let right_value = match eval(*right) {
-              Ok(x) => x,
-              err @ Err(_) => return err
-          };
-          
-          // if no errors so far, then do the operation and return if any errors
-          Ok(match op {
-              Operation::Add => left_value + right_value,
-              Operation::Sub => left_value - right_value,
-              Operation::Mul => left_value * right_value,
-              Operation::Div => {
-                if right_value == 0 {
-                    return Err(String::from("division by zero"));
-                } else {    
-                    left_value / right_value
+        Expression::Op { left, right, .. } => {
+            take(left);
+            take(right);
+        }
+        Expression::Let { value, body, .. } => {
+            take(value);
+            take(body);
+        }
+        Expression::Unary { operand, .. } => take(operand),
+        Expression::If { cond, then, else_ } => {
+            take(cond);
+            take(then);
+            take(else_);
+        }
+        Expression::Value(_) | Expression::Var(_) => (),
+    }
+}
+
+/// The values of the variables an expression is evaluated with.
+#[derive(Debug, Default)]
+struct Env {
+    vars: HashMap<String, Value>,
+}
+
+impl Env {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind `name` to `value`, replacing any previous binding.
+    fn set(&mut self, name: impl Into<String>, value: Value) {
+        self.vars.insert(name.into(), value);
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+}
+
+/// How integer arithmetic that overflows an `i64` is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ArithmeticMode {
+    /// Report an `EvalErrorKind::Overflow`.
+    #[default]
+    Checked,
+    /// Wrap around in two's complement.
+    Wrapping,
+    /// Clamp to `i64::MIN` or `i64::MAX`.
+    Saturating,
+}
+
+impl ArithmeticMode {
+    /// Apply `op` to two values, promoting integers to floats where needed.
+    fn apply(self, op: &Operation, left: &Value, right: &Value) -> Result<Value, EvalErrorKind> {
+        let mismatch = || EvalErrorKind::TypeMismatch {
+            op: op.symbol(),
+            operands: vec![left.type_of(), right.type_of()],
+        };
+        if let Operation::Eq
+        | Operation::Ne
+        | Operation::Lt
+        | Operation::Le
+        | Operation::Gt
+        | Operation::Ge = op
+        {
+            let ordering = match (left, right) {
+                (Value::Int(l), Value::Int(r)) => Some(l.cmp(r)),
+                (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+                (Value::Str(l), Value::Str(r)) => Some(l.cmp(r)),
+                // NaN is unordered, so only `!=` holds for it.
+                _ => match (left.as_f64(), right.as_f64()) {
+                    (Some(l), Some(r)) => l.partial_cmp(&r),
+                    _ => return Err(mismatch()),
+                },
+            };
+            let holds = match op {
+                Operation::Eq => ordering == Some(Ordering::Equal),
+                Operation::Ne => ordering != Some(Ordering::Equal),
+                Operation::Lt => ordering == Some(Ordering::Less),
+                Operation::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                Operation::Gt => ordering == Some(Ordering::Greater),
+                _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            };
+            return Ok(Value::Bool(holds));
+        }
+        match (left, right) {
+            (Value::Int(l), Value::Int(r)) if !matches!(op, Operation::And | Operation::Or) => {
+                self.apply_int(op, *l, *r).map(Value::Int)
+            }
+            (Value::Bool(l), Value::Bool(r)) => match op {
+                Operation::And | Operation::BitAnd => Ok(Value::Bool(l & r)),
+                Operation::Or | Operation::BitOr => Ok(Value::Bool(l | r)),
+                Operation::BitXor => Ok(Value::Bool(l ^ r)),
+                _ => Err(mismatch()),
+            },
+            (Value::Str(l), Value::Str(r)) if *op == Operation::Add => {
+                Ok(Value::Str(format!("{l}{r}")))
+            }
+            _ => {
+                let (Some(l), Some(r)) = (left.as_f64(), right.as_f64()) else {
+                    return Err(mismatch());
+                };
+                Ok(Value::Float(match op {
+                    Operation::Add => l + r,
+                    Operation::Sub => l - r,
+                    Operation::Mul => l * r,
+                    Operation::Div => l / r,
+                    Operation::Rem => l % r,
+                    Operation::Pow => l.powf(r),
+                    _ => return Err(mismatch()),
+                }))
+            }
+        }
+    }
+
+    /// Apply `op` to one value.
+    fn apply_unary(self, op: &UnaryOp, value: &Value) -> Result<Value, EvalErrorKind> {
+        match (op, value) {
+            (UnaryOp::Neg, Value::Int(x)) => self.negate(*x).map(Value::Int),
+            (UnaryOp::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+            (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            // As in Rust, `!` flips the bits of an integer.
+            (UnaryOp::Not, Value::Int(x)) => Ok(Value::Int(!x)),
+            _ => Err(EvalErrorKind::TypeMismatch {
+                op: op.symbol(),
+                operands: vec![value.type_of()],
+            }),
+        }
+    }
+
+    /// Apply an arithmetic or bitwise `op` to two integers. Dividing by zero,
+    /// and negative exponents and shift amounts, are errors in every mode.
+    fn apply_int(self, op: &Operation, left: i64, right: i64) -> Result<i64, EvalErrorKind> {
+        match op {
+            Operation::Div | Operation::Rem if right == 0 => {
+                return Err(EvalErrorKind::DivisionByZero);
+            }
+            Operation::Pow | Operation::Shl | Operation::Shr if right < 0 => {
+                return Err(EvalErrorKind::NegativeAmount);
+            }
+            _ => (),
+        }
+        let exact = match op {
+            Operation::Add => left.checked_add(right),
+            Operation::Sub => left.checked_sub(right),
+            Operation::Mul => left.checked_mul(right),
+            Operation::Div => left.checked_div(right),
+            // The remainder always fits, even for `i64::MIN % -1`.
+            Operation::Rem => Some(left.wrapping_rem(right)),
+            Operation::Pow => checked_pow(left, right as u64),
+            Operation::Shl => checked_shl(left, right as u64),
+            // Shifting right rounds towards negative infinity, so it can't
+            // overflow.
+            Operation::Shr => Some(left >> right.min(63)),
+            Operation::BitAnd => Some(left & right),
+            Operation::BitOr => Some(left | right),
+            Operation::BitXor => Some(left ^ right),
+            _ => unreachable!("{op:?} is not an integer operation"),
+        };
+        if let Some(value) = exact {
+            return Ok(value);
+        }
+        Ok(match self {
+            ArithmeticMode::Checked => return Err(EvalErrorKind::Overflow),
+            ArithmeticMode::Wrapping => match op {
+                Operation::Add => left.wrapping_add(right),
+                Operation::Sub => left.wrapping_sub(right),
+                Operation::Mul => left.wrapping_mul(right),
+                Operation::Div => left.wrapping_div(right),
+                Operation::Pow => wrapping_pow(left, right as u64),
+                Operation::Shl if right >= 64 => 0,
+                Operation::Shl => left << right,
+                _ => unreachable!("{op:?} cannot overflow"),
+            },
+            ArithmeticMode::Saturating => match op {
+                Operation::Add => left.saturating_add(right),
+                Operation::Sub => left.saturating_sub(right),
+                Operation::Mul => left.saturating_mul(right),
+                Operation::Div => left.saturating_div(right),
+                Operation::Pow if left < 0 && right % 2 == 1 => i64::MIN,
+                Operation::Shl if left < 0 => i64::MIN,
+                Operation::Pow | Operation::Shl => i64::MAX,
+                _ => unreachable!("{op:?} cannot overflow"),
+            },
+        })
+    }
+
+    /// Negate an integer.
+    fn negate(self, value: i64) -> Result<i64, EvalErrorKind> {
+        match self {
+            ArithmeticMode::Checked => value.checked_neg().ok_or(EvalErrorKind::Overflow),
+            ArithmeticMode::Wrapping => Ok(value.wrapping_neg()),
+            ArithmeticMode::Saturating => Ok(value.saturating_neg()),
+        }
+    }
+}
+
+/// `base` raised to the power `exp`, or `None` if that overflows.
+fn checked_pow(mut base: i64, mut exp: u64) -> Option<i64> {
+    let mut result: i64 = 1;
+    loop {
+        if exp & 1 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exp >>= 1;
+        if exp == 0 {
+            return Some(result);
+        }
+        base = base.checked_mul(base)?;
+    }
+}
+
+/// `base` raised to the power `exp`, wrapping around on overflow.
+fn wrapping_pow(mut base: i64, mut exp: u64) -> i64 {
+    let mut result: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// `value` multiplied by 2 to the power `amount`, or `None` if that overflows.
+fn checked_shl(value: i64, amount: u64) -> Option<i64> {
+    if value == 0 {
+        return Some(0);
+    }
+    let shifted = value.checked_shl(u32::try_from(amount).ok()?)?;
+    (shifted >> amount == value).then_some(shifted)
+}
+
+/// What went wrong while evaluating an expression.
+#[derive(Debug, PartialEq)]
+enum EvalErrorKind {
+    DivisionByZero,
+    /// A result that does not fit in an `i64`, in `ArithmeticMode::Checked`.
+    Overflow,
+    /// A negative exponent or shift amount.
+    NegativeAmount,
+    /// A variable that is neither in the environment nor bound by a `Let`.
+    UnboundVariable(String),
+    /// Operands, or an `If` condition, of types the operation doesn't accept.
+    TypeMismatch { op: &'static str, operands: Vec<Type> },
+}
+
+/// An error from evaluating an expression, with the path from the root to
+/// the subexpression that failed.
+#[derive(Debug, PartialEq)]
+struct EvalError {
+    kind: EvalErrorKind,
+    path: Vec<Step>,
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            EvalErrorKind::DivisionByZero => write!(f, "division by zero")?,
+            EvalErrorKind::Overflow => write!(f, "arithmetic overflow")?,
+            EvalErrorKind::NegativeAmount => write!(f, "negative exponent or shift amount")?,
+            EvalErrorKind::UnboundVariable(name) => write!(f, "unbound variable `{name}`")?,
+            EvalErrorKind::TypeMismatch { op, operands } => {
+                write!(f, "`{op}` cannot be applied to ")?;
+                for (i, operand) in operands.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { " and " };
+                    write!(f, "{separator}{operand}")?;
                 }
-              }
-          })
-      }
+            }
+        }
+        write!(f, " at root")?;
+        for step in &self.path {
+            write!(f, ".{}", step.name())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+fn eval(e: Expression) -> Result<Value, EvalError> {
+    eval_with(&e, &Env::new())
+}
+
+/// Evaluate an expression with checked arithmetic, looking up its variables
+/// in `env`.
+fn eval_with(e: &Expression, env: &Env) -> Result<Value, EvalError> {
+    eval_with_mode(e, env, ArithmeticMode::Checked)
+}
+
+/// Evaluate an expression, handling overflow as `mode` says. The expression is
+/// compiled first, since `Program::run` handles trees of any depth.
+fn eval_with_mode(e: &Expression, env: &Env, mode: ArithmeticMode) -> Result<Value, EvalError> {
+    compile(e).run_with_mode(env, mode)
+}
+
+/// Evaluate an expression by walking the tree, which recurses once per level.
+/// The tests check the compiled program against it.
+#[cfg(test)]
+fn walk_with_mode(e: &Expression, env: &Env, mode: ArithmeticMode) -> Result<Value, EvalError> {
+    Evaluator { env, mode, scope: Vec::new(), path: Vec::new() }.eval(e)
+}
+
+/// The state of a tree-walking evaluation.
+#[cfg(test)]
+struct Evaluator<'e, 'v> {
+    env: &'v Env,
+    mode: ArithmeticMode,
+    /// The variables bound by the enclosing `Let`s, innermost last, which
+    /// shadow those in `env`.
+    scope: Vec<(&'e str, Value)>,
+    /// The path from the root to the expression being evaluated.
+    path: Vec<Step>,
+}
+
+#[cfg(test)]
+impl<'e> Evaluator<'e, '_> {
+    fn eval(&mut self, e: &'e Expression) -> Result<Value, EvalError> {
+        match e {
+            // Base cases
+            Expression::Value(x) => Ok(x.clone()),
+            Expression::Var(name) => self
+                .scope
+                .iter()
+                .rev()
+                .find(|(bound, _)| bound == name)
+                .map(|(_, value)| value)
+                .or_else(|| self.env.get(name))
+                .cloned()
+                .ok_or_else(|| self.error(EvalErrorKind::UnboundVariable(name.clone()))),
+
+            // Recursive cases
+            Expression::Let { name, value, body } => {
+                let value = self.eval_child(Step::Value, value)?;
+                self.scope.push((name, value));
+                let result = self.eval_child(Step::Body, body);
+                self.scope.pop();
+                result
+            }
+            Expression::Unary { op, operand } => {
+                let value = self.eval_child(Step::Operand, operand)?;
+                self.mode.apply_unary(op, &value).map_err(|kind| self.error(kind))
+            }
+            // Only the branch that is taken is evaluated.
+            Expression::If { cond, then, else_ } => match self.eval_child(Step::Cond, cond)? {
+                Value::Bool(true) => self.eval_child(Step::Then, then),
+                Value::Bool(false) => self.eval_child(Step::Else, else_),
+                other => Err(self.error(EvalErrorKind::TypeMismatch {
+                    op: "if",
+                    operands: vec![other.type_of()],
+                })),
+            },
+            Expression::Op { op, left, right } => {
+                let left_value = self.eval_child(Step::Left, left)?;
+                match (op, &left_value) {
+                    (Operation::And, Value::Bool(false)) | (Operation::Or, Value::Bool(true)) => {
+                        return Ok(left_value);
+                    }
+                    _ => (),
+                }
+                let right_value = self.eval_child(Step::Right, right)?;
+                self.mode.apply(op, &left_value, &right_value).map_err(|kind| self.error(kind))
+            }
+        }
+    }
+
+    fn eval_child(&mut self, step: Step, e: &'e Expression) -> Result<Value, EvalError> {
+        self.path.push(step);
+        let result = self.eval(e);
+        self.path.pop();
+        result
+    }
+
+    /// An error about the expression being evaluated.
+    fn error(&self, kind: EvalErrorKind) -> EvalError {
+        EvalError { kind, path: self.path.clone() }
+    }
+}
+
+/// An instruction for the stack machine that `compile` targets.
+#[derive(Debug, Clone, PartialEq)]
+enum Instruction {
+    /// Push a constant.
+    Push(Value),
+    /// Push the value of a variable from the environment, given its index in
+    /// `Program::inputs`.
+    Input(usize),
+    /// Push the value of the variable bound by a `Let` to a slot.
+    Load(usize),
+    /// Pop a value into a slot, to bind a `Let`'s variable.
+    Store(usize),
+    /// Replace the value on top of the stack with the result of an operation.
+    Unary(UnaryOp),
+    /// Pop the right operand and then the left one, and push the result.
+    Binary(Operation),
+    /// Jump to the target if the value on top of the stack is the given
+    /// boolean, leaving it there. `&&` and `||` use this to skip their right
+    /// operand.
+    JumpIfBool(bool, usize),
+    /// Pop the condition of an `If`, and jump to the target if it is false.
+    BranchIfFalse(usize),
+    Jump(usize),
+}
+
+/// An expression compiled to bytecode, which can be run any number of times
+/// against different environments.
+#[derive(Debug)]
+struct Program {
+    instructions: Vec<Instruction>,
+    /// The names of the variables looked up in the environment.
+    inputs: Vec<String>,
+    /// The number of slots for variables bound by `Let`s.
+    slots: usize,
+    /// The paths from the root to the expressions the instructions were
+    /// compiled from, for errors. They are stored as a tree of `(parent,
+    /// step)` entries, where entry `i` is numbered `i + 1` and 0 is the root.
+    /// `origins` holds the number of each instruction's path.
+    paths: Vec<(usize, Step)>,
+    origins: Vec<usize>,
+}
+
+/// A unit of work for `compile`, which keeps a list of them rather than
+/// recursing so that deep trees can be compiled.
+enum Task<'e> {
+    Compile(&'e Expression),
+    Enter(Step),
+    Leave,
+    Emit(Instruction),
+    /// Emit a jump whose target is set by a later `Patch`.
+    EmitJump(Instruction),
+    /// Point the most recent jump that has no target at the next instruction.
+    Patch,
+    /// Jump from the end of an `If`'s `then` branch to its end, and point the
+    /// condition's jump at the `else` branch.
+    Else,
+    Bind(&'e str),
+    Unbind,
+}
+
+/// Compile an expression to bytecode for `Program::run`.
+fn compile(e: &Expression) -> Program {
+    let mut program = Program {
+        instructions: Vec::new(),
+        inputs: Vec::new(),
+        slots: 0,
+        paths: Vec::new(),
+        origins: Vec::new(),
+    };
+    // Variables bound by the enclosing `Let`s, innermost last, with their
+    // slots.
+    let mut scope: Vec<(&str, usize)> = Vec::new();
+    let mut unpatched = Vec::new();
+    let mut path = 0;
+    let mut tasks = vec![Task::Compile(e)];
+    while let Some(task) = tasks.pop() {
+        let instruction = match task {
+            Task::Compile(e) => match e {
+                Expression::Value(value) => Instruction::Push(value.clone()),
+                Expression::Var(name) => {
+                    if let Some(&(_, slot)) = scope.iter().rev().find(|(bound, _)| bound == name) {
+                        Instruction::Load(slot)
+                    } else if let Some(index) = program.inputs.iter().position(|n| n == name) {
+                        Instruction::Input(index)
+                    } else {
+                        program.inputs.push(name.clone());
+                        Instruction::Input(program.inputs.len() - 1)
+                    }
+                }
+                // Tasks are taken from the end, so they are pushed in reverse.
+                Expression::Let { name, value, body } => {
+                    tasks.extend([
+                        Task::Unbind,
+                        Task::Leave,
+                        Task::Compile(body),
+                        Task::Enter(Step::Body),
+                        Task::Bind(name),
+                        Task::Leave,
+                        Task::Compile(value),
+                        Task::Enter(Step::Value),
+                    ]);
+                    continue;
+                }
+                Expression::Unary { op, operand } => {
+                    tasks.extend([
+                        Task::Emit(Instruction::Unary(*op)),
+                        Task::Leave,
+                        Task::Compile(operand),
+                        Task::Enter(Step::Operand),
+                    ]);
+                    continue;
+                }
+                Expression::If { cond, then, else_ } => {
+                    tasks.extend([
+                        Task::Patch,
+                        Task::Leave,
+                        Task::Compile(else_),
+                        Task::Enter(Step::Else),
+                        Task::Else,
+                        Task::Leave,
+                        Task::Compile(then),
+                        Task::Enter(Step::Then),
+                        Task::EmitJump(Instruction::BranchIfFalse(0)),
+                        Task::Leave,
+                        Task::Compile(cond),
+                        Task::Enter(Step::Cond),
+                    ]);
+                    continue;
+                }
+                Expression::Op { op, left, right } => {
+                    let short_circuit = match op {
+                        Operation::And => Some(false),
+                        Operation::Or => Some(true),
+                        _ => None,
+                    };
+                    if short_circuit.is_some() {
+                        tasks.push(Task::Patch);
+                    }
+                    tasks.extend([
+                        Task::Emit(Instruction::Binary(*op)),
+                        Task::Leave,
+                        Task::Compile(right),
+                        Task::Enter(Step::Right),
+                    ]);
+                    if let Some(skip_if) = short_circuit {
+                        tasks.push(Task::EmitJump(Instruction::JumpIfBool(skip_if, 0)));
+                    }
+                    tasks.extend([Task::Leave, Task::Compile(left), Task::Enter(Step::Left)]);
+                    continue;
+                }
+            },
+            Task::Enter(step) => {
+                program.paths.push((path, step));
+                path = program.paths.len();
+                continue;
+            }
+            Task::Leave => {
+                path = program.paths[path - 1].0;
+                continue;
+            }
+            Task::Emit(instruction) => instruction,
+            Task::EmitJump(instruction) => {
+                unpatched.push(program.instructions.len());
+                instruction
+            }
+            Task::Patch => {
+                let jump = unpatched.pop().expect("a jump to patch");
+                program.set_target(jump);
+                continue;
+            }
+            Task::Else => {
+                let branch = unpatched.pop().expect("the condition's jump");
+                unpatched.push(program.instructions.len());
+                program.instructions.push(Instruction::Jump(0));
+                program.origins.push(path);
+                program.set_target(branch);
+                continue;
+            }
+            Task::Bind(name) => {
+                scope.push((name, program.slots));
+                program.slots += 1;
+                Instruction::Store(program.slots - 1)
+            }
+            Task::Unbind => {
+                scope.pop();
+                continue;
+            }
+        };
+        program.instructions.push(instruction);
+        program.origins.push(path);
+    }
+    program
+}
+
+impl Program {
+    /// Point the jump at `index` at the next instruction.
+    fn set_target(&mut self, index: usize) {
+        let next = self.instructions.len();
+        match &mut self.instructions[index] {
+            Instruction::JumpIfBool(_, target)
+            | Instruction::BranchIfFalse(target)
+            | Instruction::Jump(target) => *target = next,
+            instruction => unreachable!("{instruction:?} is not a jump"),
+        }
+    }
+
+    /// Run the program with checked arithmetic, looking up its variables in
+    /// `env`. The result, including any error, is the same as walking the
+    /// tree would give.
+    fn run(&self, env: &Env) -> Result<Value, EvalError> {
+        self.run_with_mode(env, ArithmeticMode::Checked)
+    }
+
+    /// Run the program, handling overflow as `mode` says.
+    fn run_with_mode(&self, env: &Env, mode: ArithmeticMode) -> Result<Value, EvalError> {
+        let inputs: Vec<Option<&Value>> = self.inputs.iter().map(|name| env.get(name)).collect();
+        let mut slots = vec![Value::Bool(false); self.slots];
+        let mut stack = Vec::new();
+        let mut pc = 0;
+        while let Some(instruction) = self.instructions.get(pc) {
+            let current = pc;
+            let error = |kind| EvalError { kind, path: self.path(current) };
+            pc += 1;
+            match instruction {
+                Instruction::Push(value) => stack.push(value.clone()),
+                Instruction::Input(index) => match inputs[*index] {
+                    Some(value) => stack.push(value.clone()),
+                    None => {
+                        let name = self.inputs[*index].clone();
+                        return Err(error(EvalErrorKind::UnboundVariable(name)));
+                    }
+                },
+                Instruction::Load(slot) => stack.push(slots[*slot].clone()),
+                Instruction::Store(slot) => slots[*slot] = pop(&mut stack),
+                Instruction::Unary(op) => {
+                    let value = pop(&mut stack);
+                    stack.push(mode.apply_unary(op, &value).map_err(error)?);
+                }
+                Instruction::Binary(op) => {
+                    let right = pop(&mut stack);
+                    let left = pop(&mut stack);
+                    stack.push(mode.apply(op, &left, &right).map_err(error)?);
+                }
+                Instruction::JumpIfBool(value, target) => {
+                    if stack.last() == Some(&Value::Bool(*value)) {
+                        pc = *target;
+                    }
+                }
+                Instruction::BranchIfFalse(target) => match pop(&mut stack) {
+                    Value::Bool(true) => (),
+                    Value::Bool(false) => pc = *target,
+                    other => {
+                        let operands = vec![other.type_of()];
+                        return Err(error(EvalErrorKind::TypeMismatch { op: "if", operands }));
+                    }
+                },
+                Instruction::Jump(target) => pc = *target,
+            }
+        }
+        Ok(pop(&mut stack))
+    }
+
+    /// The path to the expression the instruction at `pc` was compiled from.
+    fn path(&self, pc: usize) -> Vec<Step> {
+        let mut path = Vec::new();
+        let mut entry = self.origins[pc];
+        while entry != 0 {
+            let (parent, step) = self.paths[entry - 1];
+            path.push(step);
+            entry = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> Value {
+    stack.pop().expect("compiled code never pops an empty stack")
+}
+
+/// A token of an arithmetic expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// An integer literal's magnitude, which the parser checks against
+    /// `i64` once it knows whether the literal is negated.
+    Number(u64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Ident(String),
+    Let,
+    In,
+    If,
+    Then,
+    Else,
+    Equals,
+    Plus,
+    Minus,
+    Star,
+    StarStar,
+    Slash,
+    Percent,
+    Amp,
+    AmpAmp,
+    Pipe,
+    PipePipe,
+    Caret,
+    Bang,
+    BangEquals,
+    EqualsEquals,
+    Less,
+    LessEquals,
+    LessLess,
+    Greater,
+    GreaterEquals,
+    GreaterGreater,
+    LeftParen,
+    RightParen,
+}
+
+/// What went wrong while parsing an expression.
+#[derive(Debug, PartialEq)]
+enum ParseErrorKind {
+    /// A character that cannot start a token.
+    UnexpectedChar(char),
+    /// A token where an operand or operator was expected.
+    UnexpectedToken(Token),
+    /// The input ended in the middle of an expression.
+    UnexpectedEnd,
+    /// An integer literal that does not fit in an `i64`.
+    IntegerOverflow,
+    /// A string literal without its closing quote.
+    UnterminatedString,
+    /// Parentheses, operators or `let`s nested more than `MAX_DEPTH` deep.
+    TooDeep,
+}
+
+/// An error from `parse_expression`, at a byte offset into the input.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    position: usize,
+    kind: ParseErrorKind,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedChar(c) => write!(f, "unexpected character {c:?}")?,
+            ParseErrorKind::UnexpectedToken(token) => write!(f, "unexpected {token:?}")?,
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            ParseErrorKind::IntegerOverflow => write!(f, "integer literal too large")?,
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string")?,
+            ParseErrorKind::TooDeep => write!(f, "expression nested too deeply")?,
+        }
+        write!(f, " at offset {}", self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split the input into tokens, each paired with its byte offset.
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    // Consume the next character if it is `expected`, for two-character
+    // operators.
+    let followed_by = |chars: &mut std::iter::Peekable<std::str::CharIndices>, expected| {
+        chars.next_if(|&(_, c)| c == expected).is_some()
+    };
+    while let Some((position, c)) = chars.next() {
+        let token = match c {
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' if followed_by(&mut chars, '*') => Token::StarStar,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '&' if followed_by(&mut chars, '&') => Token::AmpAmp,
+            '&' => Token::Amp,
+            '|' if followed_by(&mut chars, '|') => Token::PipePipe,
+            '|' => Token::Pipe,
+            '^' => Token::Caret,
+            '!' if followed_by(&mut chars, '=') => Token::BangEquals,
+            '!' => Token::Bang,
+            '=' if followed_by(&mut chars, '=') => Token::EqualsEquals,
+            '=' => Token::Equals,
+            '<' if followed_by(&mut chars, '=') => Token::LessEquals,
+            '<' if followed_by(&mut chars, '<') => Token::LessLess,
+            '<' => Token::Less,
+            '>' if followed_by(&mut chars, '=') => Token::GreaterEquals,
+            '>' if followed_by(&mut chars, '>') => Token::GreaterGreater,
+            '>' => Token::Greater,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            c if c.is_whitespace() => continue,
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = position + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !c.is_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                match &input[position..end] {
+                    "let" => Token::Let,
+                    "in" => Token::In,
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    "true" => Token::Bool(true),
+                    "false" => Token::Bool(false),
+                    name => Token::Ident(name.to_string()),
+                }
+            }
+            // A number with a decimal point followed by a digit is a float.
+            c if c.is_ascii_digit() => {
+                let digits_end = |from: usize| {
+                    let length = input[from..].find(|c: char| !c.is_ascii_digit());
+                    length.map_or(input.len(), |length| from + length)
+                };
+                let mut end = digits_end(position);
+                let is_float = input[end..].starts_with('.')
+                    && input[end + 1..].starts_with(|c: char| c.is_ascii_digit());
+                if is_float {
+                    end = digits_end(end + 1);
+                }
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                let text = &input[position..end];
+                if is_float {
+                    Token::Float(text.parse().expect("digits around a decimal point"))
+                } else {
+                    let value = text.parse().map_err(|_| ParseError {
+                        position,
+                        kind: ParseErrorKind::IntegerOverflow,
+                    })?;
+                    Token::Number(value)
+                }
+            }
+            '"' => {
+                let unterminated =
+                    || ParseError { position, kind: ParseErrorKind::UnterminatedString };
+                let mut text = String::new();
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        (_, '"') => break,
+                        (_, '\\') => match chars.next().ok_or_else(unterminated)? {
+                            (_, c @ ('"' | '\\')) => text.push(c),
+                            (_, 'n') => text.push('\n'),
+                            (_, 't') => text.push('\t'),
+                            (i, c) => {
+                                let kind = ParseErrorKind::UnexpectedChar(c);
+                                return Err(ParseError { position: i, kind });
+                            }
+                        },
+                        (_, c) => text.push(c),
+                    }
+                }
+                Token::Str(text)
+            }
+            c => return Err(ParseError { position, kind: ParseErrorKind::UnexpectedChar(c) }),
+        };
+        tokens.push((token, position));
+    }
+    Ok(tokens)
+}
+
+/// How deeply operands may nest, so that the recursive parser fails with
+/// `TooDeep` rather than overflowing the stack. Chains such as `1 + 1 + 1`
+/// are parsed in a loop and may be any length.
+const MAX_DEPTH: usize = 256;
+
+/// A precedence-climbing parser over a list of tokens.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    /// The length of the input, which is where errors at the end are reported.
+    end: usize,
+    /// The number of operands being parsed, each inside the one before.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    /// Consume the next token, which must be `expected`.
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        if self.peek() != Some(&expected) {
+            return Err(self.unexpected());
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    /// An error for the next token, or for the end of the input.
+    fn unexpected(&self) -> ParseError {
+        match self.tokens.get(self.next) {
+            Some((token, position)) => ParseError {
+                position: *position,
+                kind: ParseErrorKind::UnexpectedToken(token.clone()),
+            },
+            None => ParseError { position: self.end, kind: ParseErrorKind::UnexpectedEnd },
+        }
+    }
+
+    /// Parse binary operations whose operators bind at least as tightly as
+    /// `min_precedence`. All the operators are left associative.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, ParseError> {
+        let mut left = self.parse_unary()?;
+        while let Some((op, precedence)) = self.peek().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.next += 1;
+            let right = self.parse_binary(precedence + 1)?;
+            left = Expression::Op { op, left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
     }
+
+    /// Parse an operand, which may be negated with `-` or `!`. Every level of
+    /// nesting passes through here, so this is where the depth is limited.
+    fn parse_unary(&mut self) -> Result<Expression, ParseError> {
+        if self.depth == MAX_DEPTH {
+            let position = self.tokens.get(self.next).map_or(self.end, |(_, position)| *position);
+            return Err(ParseError { position, kind: ParseErrorKind::TooDeep });
+        }
+        self.depth += 1;
+        let operand = self.parse_unary_operand();
+        self.depth -= 1;
+        operand
+    }
+
+    fn parse_unary_operand(&mut self) -> Result<Expression, ParseError> {
+        let op = match self.peek() {
+            Some(Token::Minus) => UnaryOp::Neg,
+            Some(Token::Bang) => UnaryOp::Not,
+            _ => return self.parse_power(),
+        };
+        self.next += 1;
+        // A negated integer literal is a single value, which is the only way to
+        // write `i64::MIN`. Not when it is the base of a power, which binds
+        // more tightly than the negation.
+        if let (Some(&(Token::Number(magnitude), position)), UnaryOp::Neg) =
+            (self.tokens.get(self.next), op)
+        {
+            if self.tokens.get(self.next + 1).map(|(token, _)| token) != Some(&Token::StarStar) {
+                self.next += 1;
+                let value = 0i64.checked_sub_unsigned(magnitude).ok_or(ParseError {
+                    position,
+                    kind: ParseErrorKind::IntegerOverflow,
+                })?;
+                return Ok(Expression::Value(Value::Int(value)));
+            }
+        }
+        let operand = self.parse_unary()?;
+        Ok(Expression::Unary { op, operand: Box::new(operand) })
+    }
+
+    /// Parse a power, which binds more tightly than negation on its left, so
+    /// that `-2 ** 2` is -4, and is right associative.
+    fn parse_power(&mut self) -> Result<Expression, ParseError> {
+        let base = self.parse_primary()?;
+        if self.peek() != Some(&Token::StarStar) {
+            return Ok(base);
+        }
+        self.next += 1;
+        let exponent = self.parse_unary()?;
+        Ok(Expression::Op { op: Operation::Pow, left: Box::new(base), right: Box::new(exponent) })
+    }
+
+    /// Parse a literal, a variable, a parenthesised expression, a `let` or an
+    /// `if`.
+    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+        let Some(token) = self.peek() else {
+            return Err(self.unexpected());
+        };
+        match token {
+            Token::Number(_) | Token::Float(_) | Token::Str(_) | Token::Bool(_) => {
+                let position = self.tokens[self.next].1;
+                let value = match token.clone() {
+                    Token::Number(magnitude) => Value::Int(i64::try_from(magnitude).map_err(
+                        |_| ParseError { position, kind: ParseErrorKind::IntegerOverflow },
+                    )?),
+                    Token::Float(value) => Value::Float(value),
+                    Token::Str(text) => Value::Str(text),
+                    Token::Bool(value) => Value::Bool(value),
+                    _ => unreachable!("the token is a literal"),
+                };
+                self.next += 1;
+                Ok(Expression::Value(value))
+            }
+            Token::Ident(name) => {
+                let name = name.clone();
+                self.next += 1;
+                Ok(Expression::Var(name))
+            }
+            // The body of a `let` extends as far to the right as possible.
+            Token::Let => {
+                self.next += 1;
+                let Some(Token::Ident(name)) = self.peek() else {
+                    return Err(self.unexpected());
+                };
+                let name = name.clone();
+                self.next += 1;
+                self.expect(Token::Equals)?;
+                let value = self.parse_binary(0)?;
+                self.expect(Token::In)?;
+                let body = self.parse_binary(0)?;
+                Ok(Expression::Let { name, value: Box::new(value), body: Box::new(body) })
+            }
+            // So does the `else` branch of an `if`.
+            Token::If => {
+                self.next += 1;
+                let cond = self.parse_binary(0)?;
+                self.expect(Token::Then)?;
+                let then = self.parse_binary(0)?;
+                self.expect(Token::Else)?;
+                let else_ = self.parse_binary(0)?;
+                Ok(Expression::If {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    else_: Box::new(else_),
+                })
+            }
+            Token::LeftParen => {
+                self.next += 1;
+                let inner = self.parse_binary(0)?;
+                self.expect(Token::RightParen)?;
+                Ok(inner)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+/// The operation and precedence of a binary operator token, which follow
+/// Rust's. Powers are handled by `Parser::parse_power`.
+fn binary_operator(token: &Token) -> Option<(Operation, u8)> {
+    let (op, precedence) = match token {
+        Token::PipePipe => (Operation::Or, 1),
+        Token::AmpAmp => (Operation::And, 2),
+        Token::EqualsEquals => (Operation::Eq, 3),
+        Token::BangEquals => (Operation::Ne, 3),
+        Token::Less => (Operation::Lt, 3),
+        Token::LessEquals => (Operation::Le, 3),
+        Token::Greater => (Operation::Gt, 3),
+        Token::GreaterEquals => (Operation::Ge, 3),
+        Token::Pipe => (Operation::BitOr, 4),
+        Token::Caret => (Operation::BitXor, 5),
+        Token::Amp => (Operation::BitAnd, 6),
+        Token::LessLess => (Operation::Shl, 7),
+        Token::GreaterGreater => (Operation::Shr, 7),
+        Token::Plus => (Operation::Add, 8),
+        Token::Minus => (Operation::Sub, 8),
+        Token::Star => (Operation::Mul, 9),
+        Token::Slash => (Operation::Div, 9),
+        Token::Percent => (Operation::Rem, 9),
+        _ => return None,
+    };
+    Some((op, precedence))
+}
+
+/// Parse an arithmetic expression such as `(3 - 4) * 5 + 10 * 9` into a tree.
+/// Besides integers, literals may be floats such as `2.5`, strings such as
+/// `"text"`, or `true` and `false`.
+/// Expressions may use variables, name a value with `let x = 2 * y in x * x`,
+/// and choose between values with `if x > 0 then x else -x`.
+fn parse_expression(input: &str) -> Result<Expression, ParseError> {
+    let mut parser = Parser { tokens: tokenize(input)?, next: 0, end: input.len(), depth: 0 };
+    let expression = parser.parse_binary(0)?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected());
+    }
+    Ok(expression)
 }
 
 #[test]
 fn test_value() {
-    assert_eq!(eval(Expression::Value(19)), Ok(19));
+    assert_eq!(eval(Expression::Value(Value::Int(19))), Ok(Value::Int(19)));
 }
 
 #[test]
@@ -63,10 +1307,10 @@ fn test_sum() {
     assert_eq!(
         eval(Expression::Op {
             op: Operation::Add,
-            left: Box::new(Expression::Value(10)),
-            right: Box::new(Expression::Value(20)),
+            left: Box::new(Expression::Value(Value::Int(10))),
+            right: Box::new(Expression::Value(Value::Int(20))),
         }),
-        Ok(30)
+        Ok(Value::Int(30))
     );
 }
 
@@ -74,17 +1318,17 @@ fn test_sum() {
 fn test_recursion() {
     let term1 = Expression::Op {
         op: Operation::Mul,
-        left: Box::new(Expression::Value(10)),
-        right: Box::new(Expression::Value(9)),
+        left: Box::new(Expression::Value(Value::Int(10))),
+        right: Box::new(Expression::Value(Value::Int(9))),
     };
     let term2 = Expression::Op {
         op: Operation::Mul,
         left: Box::new(Expression::Op {
             op: Operation::Sub,
-            left: Box::new(Expression::Value(3)),
-            right: Box::new(Expression::Value(4)),
+            left: Box::new(Expression::Value(Value::Int(3))),
+            right: Box::new(Expression::Value(Value::Int(4))),
         }),
-        right: Box::new(Expression::Value(5)),
+        right: Box::new(Expression::Value(Value::Int(5))),
     };
     assert_eq!(
         eval(Expression::Op {
@@ -92,7 +1336,7 @@ fn test_recursion() {
             left: Box::new(term1),
             right: Box::new(term2),
         }),
-        Ok(85)
+        Ok(Value::Int(85))
     );
 }
 
@@ -101,9 +1345,573 @@ fn test_error() {
     assert_eq!(
         eval(Expression::Op {
             op: Operation::Div,
-            left: Box::new(Expression::Value(99)),
-            right: Box::new(Expression::Value(0)),
+            left: Box::new(Expression::Value(Value::Int(99))),
+            right: Box::new(Expression::Value(Value::Int(0))),
         }),
-        Err(String::from("division by zero"))
+        Err(EvalError { kind: EvalErrorKind::DivisionByZero, path: vec![] })
+    );
+}
+
+#[test]
+fn test_parse() {
+    let expression = parse_expression("(3 - 4) * 5 + 10 * 9").unwrap();
+    assert_eq!(
+        expression,
+        Expression::Op {
+            op: Operation::Add,
+            left: Box::new(Expression::Op {
+                op: Operation::Mul,
+                left: Box::new(Expression::Op {
+                    op: Operation::Sub,
+                    left: Box::new(Expression::Value(Value::Int(3))),
+                    right: Box::new(Expression::Value(Value::Int(4))),
+                }),
+                right: Box::new(Expression::Value(Value::Int(5))),
+            }),
+            right: Box::new(Expression::Op {
+                op: Operation::Mul,
+                left: Box::new(Expression::Value(Value::Int(10))),
+                right: Box::new(Expression::Value(Value::Int(9))),
+            }),
+        }
+    );
+    assert_eq!(eval(expression), Ok(Value::Int(85)));
+}
+
+#[test]
+fn test_parse_precedence() {
+    let eval_str = |input| eval(parse_expression(input).unwrap());
+    assert_eq!(eval_str("1 + 2 * 3"), Ok(Value::Int(7)));
+    assert_eq!(eval_str("10 - 4 - 3"), Ok(Value::Int(3)));
+    assert_eq!(eval_str("100 / 10 / 5"), Ok(Value::Int(2)));
+    assert_eq!(eval_str("-2 * -(3 + 4)"), Ok(Value::Int(14)));
+    assert_eq!(eval_str("--5"), Ok(Value::Int(5)));
+    assert_eq!(eval_str("7/0").map_err(|err| err.kind), Err(EvalErrorKind::DivisionByZero));
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = |position, kind| Err(ParseError { position, kind });
+    assert_eq!(parse_expression("1 + $"), error(4, ParseErrorKind::UnexpectedChar('$')));
+    assert_eq!(parse_expression("(1 + 2"), error(6, ParseErrorKind::UnexpectedEnd));
+    assert_eq!(parse_expression(""), error(0, ParseErrorKind::UnexpectedEnd));
+    assert_eq!(
+        parse_expression("1 2"),
+        error(2, ParseErrorKind::UnexpectedToken(Token::Number(2)))
+    );
+    assert_eq!(
+        parse_expression("* 2"),
+        error(0, ParseErrorKind::UnexpectedToken(Token::Star))
+    );
+    assert_eq!(
+        parse_expression("(1))"),
+        error(3, ParseErrorKind::UnexpectedToken(Token::RightParen))
+    );
+    assert_eq!(
+        parse_expression("99999999999999999999"),
+        error(0, ParseErrorKind::IntegerOverflow)
+    );
+    assert_eq!(
+        parse_expression("1 + 9223372036854775808"),
+        error(4, ParseErrorKind::IntegerOverflow)
+    );
+    assert_eq!(
+        parse_expression("-9223372036854775809"),
+        error(1, ParseErrorKind::IntegerOverflow)
+    );
+    assert_eq!(
+        parse_expression("(1 + 2").unwrap_err().to_string(),
+        "unexpected end of input at offset 6"
     );
 }
+
+#[test]
+fn test_parse_negative_literals() {
+    assert_eq!(
+        parse_expression("-9223372036854775808"),
+        Ok(Expression::Value(Value::Int(i64::MIN)))
+    );
+    assert_eq!(parse_expression("-7"), Ok(Expression::Value(Value::Int(-7))));
+    assert_eq!(
+        parse_expression("--7"),
+        Ok(Expression::Unary {
+            op: UnaryOp::Neg,
+            operand: Box::new(Expression::Value(Value::Int(-7))),
+        })
+    );
+    // The power binds first, so the literal is not negated on its own.
+    assert_eq!(eval(parse_expression("-2 ** 2").unwrap()), Ok(Value::Int(-4)));
+    assert_eq!(
+        eval(parse_expression("3 - -9223372036854775808").unwrap()).map_err(|err| err.kind),
+        Err(EvalErrorKind::Overflow)
+    );
+}
+
+#[test]
+fn test_parse_depth() {
+    let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(eval(parse_expression(&nested(MAX_DEPTH - 1)).unwrap()), Ok(Value::Int(1)));
+    // A chain of left associative operators parses in a loop, so it is not
+    // limited, and `eval` handles the deep tree it makes.
+    let chain = format!("1{}", " + 1".repeat(199_999));
+    assert_eq!(eval(parse_expression(&chain).unwrap()), Ok(Value::Int(200_000)));
+    assert_eq!(
+        parse_expression(&nested(200_000)),
+        Err(ParseError { position: MAX_DEPTH, kind: ParseErrorKind::TooDeep })
+    );
+    assert_eq!(
+        parse_expression(&"-".repeat(200_000)).map_err(|err| err.kind),
+        Err(ParseErrorKind::TooDeep)
+    );
+    assert_eq!(
+        parse_expression(&"let x = ".repeat(200_000)).map_err(|err| err.kind),
+        Err(ParseErrorKind::TooDeep)
+    );
+    assert_eq!(
+        parse_expression(&format!("{}1", "2 ** ".repeat(200_000))).map_err(|err| err.kind),
+        Err(ParseErrorKind::TooDeep)
+    );
+}
+
+#[test]
+fn test_variables() {
+    let mut env = Env::new();
+    env.set("x", Value::Int(6));
+    env.set("y", Value::Int(7));
+    let expression = parse_expression("x * y - 2").unwrap();
+    assert_eq!(eval_with(&expression, &env), Ok(Value::Int(40)));
+    assert_eq!(
+        eval_with(&parse_expression("x + z").unwrap(), &env),
+        Err(EvalError {
+            kind: EvalErrorKind::UnboundVariable(String::from("z")),
+            path: vec![Step::Right],
+        })
+    );
+    assert_eq!(
+        eval(parse_expression("x").unwrap()).unwrap_err().to_string(),
+        "unbound variable `x` at root"
+    );
+}
+
+#[test]
+fn test_let() {
+    let mut env = Env::new();
+    env.set("x", Value::Int(3));
+    let expression = parse_expression("let sum = x + 1 in sum * sum").unwrap();
+    assert_eq!(
+        expression,
+        Expression::Let {
+            name: String::from("sum"),
+            value: Box::new(Expression::Op {
+                op: Operation::Add,
+                left: Box::new(Expression::Var(String::from("x"))),
+                right: Box::new(Expression::Value(Value::Int(1))),
+            }),
+            body: Box::new(Expression::Op {
+                op: Operation::Mul,
+                left: Box::new(Expression::Var(String::from("sum"))),
+                right: Box::new(Expression::Var(String::from("sum"))),
+            }),
+        }
+    );
+    assert_eq!(eval_with(&expression, &env), Ok(Value::Int(16)));
+
+    // Inner bindings shadow outer ones and the environment, but only within
+    // their body.
+    let expression = parse_expression("(let x = 10 in let x = x * 2 in x) + x").unwrap();
+    assert_eq!(eval_with(&expression, &env), Ok(Value::Int(23)));
+
+    assert_eq!(
+        parse_expression("let 1 = 2 in 3"),
+        Err(ParseError { position: 4, kind: ParseErrorKind::UnexpectedToken(Token::Number(1)) })
+    );
+    assert_eq!(
+        parse_expression("let x = 2"),
+        Err(ParseError { position: 9, kind: ParseErrorKind::UnexpectedEnd })
+    );
+}
+
+#[test]
+fn test_overflow() {
+    let mut env = Env::new();
+    env.set("max", Value::Int(i64::MAX));
+    env.set("min", Value::Int(i64::MIN));
+    let eval_mode = |input, mode| {
+        eval_with_mode(&parse_expression(input).unwrap(), &env, mode).map_err(|err| err.kind)
+    };
+
+    let checked = ArithmeticMode::Checked;
+    assert_eq!(eval_mode("max + 1", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("min - 1", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("max * 2", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("min / -1", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("-min", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("max - 1 + 1", checked), Ok(Value::Int(i64::MAX)));
+
+    let wrapping = ArithmeticMode::Wrapping;
+    assert_eq!(eval_mode("max + 1", wrapping), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("max * 2", wrapping), Ok(Value::Int(-2)));
+    assert_eq!(eval_mode("min / -1", wrapping), Ok(Value::Int(i64::MIN)));
+
+    let saturating = ArithmeticMode::Saturating;
+    assert_eq!(eval_mode("max + 1", saturating), Ok(Value::Int(i64::MAX)));
+    assert_eq!(eval_mode("min * 2", saturating), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("min / -1", saturating), Ok(Value::Int(i64::MAX)));
+
+    // Dividing by zero is an error whatever the mode.
+    for mode in [checked, wrapping, saturating] {
+        assert_eq!(eval_mode("1 / 0", mode), Err(EvalErrorKind::DivisionByZero));
+    }
+}
+
+#[test]
+fn test_error_path() {
+    let mut env = Env::new();
+    env.set("x", Value::Int(0));
+    let expression = parse_expression("let y = 2 * x in 10 + (y + 1) / y").unwrap();
+    let error = eval_with(&expression, &env).unwrap_err();
+    assert_eq!(error.kind, EvalErrorKind::DivisionByZero);
+    assert_eq!(error.path, [Step::Body, Step::Right]);
+    assert_eq!(error.to_string(), "division by zero at root.body.right");
+
+    // The path leads a caller to the node that failed.
+    let Some(Expression::Op { op: Operation::Div, right, .. }) = expression.at(&error.path) else {
+        panic!("expected a division");
+    };
+    assert_eq!(**right, Expression::Var(String::from("y")));
+    assert_eq!(expression.at(&[Step::Left]), None);
+}
+
+#[test]
+fn test_operators() {
+    let eval_str = |input| eval(parse_expression(input).unwrap()).map_err(|err| err.kind);
+    assert_eq!(eval_str("17 % 5"), Ok(Value::Int(2)));
+    assert_eq!(eval_str("-17 % 5"), Ok(Value::Int(-2)));
+    assert_eq!(eval_str("2 ** 10"), Ok(Value::Int(1024)));
+    assert_eq!(eval_str("2 ** 3 ** 2"), Ok(Value::Int(512)));
+    assert_eq!(eval_str("-2 ** 2"), Ok(Value::Int(-4)));
+    assert_eq!(eval_str("(-2) ** 3"), Ok(Value::Int(-8)));
+    assert_eq!(eval_str("12 & 10 | 1"), Ok(Value::Int(9)));
+    assert_eq!(eval_str("12 ^ 10"), Ok(Value::Int(6)));
+    assert_eq!(eval_str("1 << 4 + 1"), Ok(Value::Int(32)));
+    assert_eq!(eval_str("-9 >> 1"), Ok(Value::Int(-5)));
+    assert_eq!(eval_str("-9 >> 100"), Ok(Value::Int(-1)));
+    assert_eq!(eval_str("1 + 1 == 2"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("3 != 3"), Ok(Value::Bool(false)));
+    assert_eq!(eval_str("1 < 2 && 2 <= 2 && !(3 > 4) && !(4 >= 5)"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("!0 + !7"), Ok(Value::Int(-9)));
+    assert_eq!(eval_str("true && false || true"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("if 3 > 2 then 10 else 20"), Ok(Value::Int(10)));
+    assert_eq!(eval_str("1 + if false then 10 else 20 * 2"), Ok(Value::Int(41)));
+    assert_eq!(eval_str("5 % 0"), Err(EvalErrorKind::DivisionByZero));
+    assert_eq!(eval_str("2 ** -1"), Err(EvalErrorKind::NegativeAmount));
+    assert_eq!(eval_str("1 << -1"), Err(EvalErrorKind::NegativeAmount));
+}
+
+#[test]
+fn test_short_circuit() {
+    // The operand that is not needed is never evaluated, so it can't fail.
+    let eval_str = |input| eval(parse_expression(input).unwrap());
+    assert_eq!(eval_str("false && 1 / 0"), Ok(Value::Bool(false)));
+    assert_eq!(eval_str("true || missing"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("if true then 2 else 1 / 0"), Ok(Value::Int(2)));
+    assert_eq!(eval_str("if false then missing else 3"), Ok(Value::Int(3)));
+
+    let error = eval_str("true && 1 / 0").unwrap_err();
+    assert_eq!(error.path, [Step::Right]);
+    let error = eval_str("if 1 == 2 then 0 else -missing").unwrap_err();
+    assert_eq!(error.kind, EvalErrorKind::UnboundVariable(String::from("missing")));
+    assert_eq!(error.path, [Step::Else, Step::Operand]);
+}
+
+#[test]
+fn test_power_and_shift_overflow() {
+    let eval_mode = |input, mode| {
+        eval_with_mode(&parse_expression(input).unwrap(), &Env::new(), mode).map_err(|err| err.kind)
+    };
+    let checked = ArithmeticMode::Checked;
+    assert_eq!(eval_mode("2 ** 62", checked), Ok(Value::Int(1 << 62)));
+    assert_eq!(eval_mode("2 ** 63", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("(-2) ** 63", checked), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("1 ** 10000000000", checked), Ok(Value::Int(1)));
+    assert_eq!(eval_mode("(-1) ** 10000000001", checked), Ok(Value::Int(-1)));
+    assert_eq!(eval_mode("-1 << 63", checked), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("1 << 63", checked), Err(EvalErrorKind::Overflow));
+    assert_eq!(eval_mode("0 << 100", checked), Ok(Value::Int(0)));
+
+    let wrapping = ArithmeticMode::Wrapping;
+    assert_eq!(eval_mode("2 ** 64", wrapping), Ok(Value::Int(0)));
+    assert_eq!(eval_mode("3 ** 41", wrapping), Ok(Value::Int(3_i64.wrapping_pow(41))));
+    assert_eq!(eval_mode("3 << 63", wrapping), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("3 << 64", wrapping), Ok(Value::Int(0)));
+
+    let saturating = ArithmeticMode::Saturating;
+    assert_eq!(eval_mode("10 ** 100", saturating), Ok(Value::Int(i64::MAX)));
+    assert_eq!(eval_mode("(-10) ** 101", saturating), Ok(Value::Int(i64::MIN)));
+    assert_eq!(eval_mode("(-10) ** 100", saturating), Ok(Value::Int(i64::MAX)));
+    assert_eq!(eval_mode("-3 << 70", saturating), Ok(Value::Int(i64::MIN)));
+}
+
+#[test]
+fn test_values() {
+    let eval_str = |input| eval(parse_expression(input).unwrap());
+    assert_eq!(eval_str("3.5 * 2"), Ok(Value::Float(7.0)));
+    assert_eq!(eval_str("7 / 2"), Ok(Value::Int(3)));
+    assert_eq!(eval_str("7 / 2.0"), Ok(Value::Float(3.5)));
+    assert_eq!(eval_str("2 ** 0.5 * 2 ** 0.5 > 1.99"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("-1.5 % 1"), Ok(Value::Float(-0.5)));
+    assert_eq!(eval_str("1 / 0.0"), Ok(Value::Float(f64::INFINITY)));
+    assert_eq!(eval_str("1 == 1.0"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("0.0 / 0.0 == 0.0 / 0.0"), Ok(Value::Bool(false)));
+    assert_eq!(eval_str("0.0 / 0.0 != 0.0 / 0.0"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str(r#""ab" + "c" == "abc""#), Ok(Value::Bool(true)));
+    assert_eq!(eval_str(r#""apple" < "banana""#), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("false < true"), Ok(Value::Bool(true)));
+    assert_eq!(eval_str("true ^ true | false"), Ok(Value::Bool(false)));
+    assert_eq!(eval_str(r#"if 2.5 > 2 then "big" else "small""#), Ok(Value::Str("big".into())));
+
+    let mut env = Env::new();
+    env.set("price", Value::Float(4.0));
+    env.set("name", Value::Str(String::from("widget")));
+    let expression = parse_expression(r#"name == "widget" && 3.5 * price == 14"#).unwrap();
+    assert_eq!(eval_with(&expression, &env), Ok(Value::Bool(true)));
+
+    assert_eq!(Value::Float(2.0).to_string(), "2.0");
+    assert_eq!(Value::Str(String::from("a b")).to_string(), "a b");
+}
+
+#[test]
+fn test_type_errors() {
+    let eval_str = |input| eval(parse_expression(input).unwrap()).unwrap_err();
+    let error = eval_str("1 + true");
+    assert_eq!(
+        error.kind,
+        EvalErrorKind::TypeMismatch { op: "+", operands: vec![Type::Int, Type::Bool] }
+    );
+    assert_eq!(error.to_string(), "`+` cannot be applied to int and bool at root");
+
+    let error = eval_str(r#"10 * (2 - "x")"#);
+    assert_eq!(error.path, [Step::Right]);
+    assert_eq!(eval_str("1 && true").to_string(), "`&&` cannot be applied to int and bool at root");
+    assert_eq!(eval_str("1.5 & 1").to_string(), "`&` cannot be applied to float and int at root");
+    assert_eq!(eval_str(r#""a" < 1"#).to_string(), "`<` cannot be applied to str and int at root");
+    assert_eq!(eval_str("!2.0").to_string(), "`!` cannot be applied to float at root");
+    assert_eq!(eval_str(r#"-"a""#).to_string(), "`-` cannot be applied to str at root");
+    assert_eq!(
+        eval_str("if 1 then 2 else 3").to_string(),
+        "`if` cannot be applied to int at root"
+    );
+    // The left operand decides whether `&&` needs its right operand before
+    // either's type is checked.
+    assert_eq!(eval(parse_expression("false && 1").unwrap()), Ok(Value::Bool(false)));
+}
+
+#[test]
+fn test_parse_literals() {
+    assert_eq!(parse_expression("2.25"), Ok(Expression::Value(Value::Float(2.25))));
+    assert_eq!(parse_expression("true"), Ok(Expression::Value(Value::Bool(true))));
+    assert_eq!(
+        parse_expression(r#""say \"hi\"\n""#),
+        Ok(Expression::Value(Value::Str(String::from("say \"hi\"\n"))))
+    );
+    // A dot without a digit after it is not part of a number.
+    assert_eq!(
+        parse_expression("2."),
+        Err(ParseError { position: 1, kind: ParseErrorKind::UnexpectedChar('.') })
+    );
+    assert_eq!(
+        parse_expression(r#"1 + "abc"#),
+        Err(ParseError { position: 4, kind: ParseErrorKind::UnterminatedString })
+    );
+    assert_eq!(
+        parse_expression(r#""\q""#),
+        Err(ParseError { position: 2, kind: ParseErrorKind::UnexpectedChar('q') })
+    );
+}
+
+#[test]
+fn test_compile() {
+    let program = compile(&parse_expression("let a = x + 1 in a * a").unwrap());
+    assert_eq!(
+        program.instructions,
+        [
+            Instruction::Input(0),
+            Instruction::Push(Value::Int(1)),
+            Instruction::Binary(Operation::Add),
+            Instruction::Store(0),
+            Instruction::Load(0),
+            Instruction::Load(0),
+            Instruction::Binary(Operation::Mul),
+        ]
+    );
+    // The same program runs against different environments.
+    let mut env = Env::new();
+    for x in 0..5 {
+        env.set("x", Value::Int(x));
+        assert_eq!(program.run(&env), Ok(Value::Int((x + 1) * (x + 1))));
+    }
+
+    let program = compile(&parse_expression("if c then 1 else x && false").unwrap());
+    assert_eq!(
+        program.instructions,
+        [
+            Instruction::Input(0),
+            Instruction::BranchIfFalse(4),
+            Instruction::Push(Value::Int(1)),
+            Instruction::Jump(8),
+            Instruction::Input(1),
+            Instruction::JumpIfBool(false, 8),
+            Instruction::Push(Value::Bool(false)),
+            Instruction::Binary(Operation::And),
+        ]
+    );
+    assert_eq!(program.inputs, ["c", "x"]);
+}
+
+/// Random expressions for `test_compiled_matches_tree_walker`. Without a
+/// manifest there is no `rand` to depend on, and a linear congruential
+/// generator is plenty for picking among a handful of choices. It always
+/// starts from the same state, so a failing expression comes back on rerun.
+#[cfg(test)]
+struct ExpressionGenerator(u64);
+
+#[cfg(test)]
+impl ExpressionGenerator {
+    /// A number below `n`, from the high bits, which are the most random.
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+
+    /// An expression of up to `depth` levels, mixing types and variables so
+    /// that many of them fail to evaluate.
+    fn expression(&mut self, depth: u32) -> Expression {
+        const OPERATIONS: [Operation; 19] = [
+            Operation::Add,
+            Operation::Sub,
+            Operation::Mul,
+            Operation::Div,
+            Operation::Rem,
+            Operation::Pow,
+            Operation::BitAnd,
+            Operation::BitOr,
+            Operation::BitXor,
+            Operation::Shl,
+            Operation::Shr,
+            Operation::Eq,
+            Operation::Ne,
+            Operation::Lt,
+            Operation::Le,
+            Operation::Gt,
+            Operation::Ge,
+            Operation::And,
+            Operation::Or,
+        ];
+        const NAMES: [&str; 3] = ["x", "y", "z"];
+        let child = |generator: &mut Self| Box::new(generator.expression(depth - 1));
+        if depth == 0 || self.below(4) == 0 {
+            return match self.below(7) {
+                0 => Expression::Value(Value::Int(self.below(7) as i64 - 3)),
+                1 => Expression::Value(Value::Int(i64::MAX)),
+                2 => Expression::Value(Value::Float(self.below(5) as f64 / 2.0)),
+                3 => Expression::Value(Value::Bool(self.below(2) == 0)),
+                4 => Expression::Value(Value::Str(String::from("ab"))),
+                _ => Expression::Var(String::from(NAMES[self.below(3)])),
+            };
+        }
+        match self.below(6) {
+            0 => {
+                let op = if self.below(2) == 0 { UnaryOp::Neg } else { UnaryOp::Not };
+                Expression::Unary { op, operand: child(self) }
+            }
+            1 => Expression::If { cond: child(self), then: child(self), else_: child(self) },
+            2 => Expression::Let {
+                name: String::from(NAMES[self.below(3)]),
+                value: child(self),
+                body: child(self),
+            },
+            _ => Expression::Op {
+                op: OPERATIONS[self.below(OPERATIONS.len())],
+                left: child(self),
+                right: child(self),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_compiled_matches_tree_walker() {
+    let mut generator = ExpressionGenerator(0);
+    let mut env = Env::new();
+    env.set("x", Value::Int(3));
+    env.set("y", Value::Bool(true));
+    let modes = [ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating];
+    for _ in 0..5000 {
+        let expression = generator.expression(6);
+        let program = compile(&expression);
+        for mode in modes {
+            // Debug formatting makes NaN results compare equal.
+            let expected = format!("{:?}", walk_with_mode(&expression, &env, mode));
+            let actual = format!("{:?}", program.run_with_mode(&env, mode));
+            assert_eq!(actual, expected, "{expression:?} in {mode:?}");
+        }
+    }
+}
+
+#[test]
+fn test_deep_expression() {
+    // Far deeper than the tree walker could recurse, which `eval` never does.
+    let mut expression = Expression::Var(String::from("x"));
+    for i in 0..200_000 {
+        let (left, right) = (Box::new(expression), Box::new(Expression::Value(Value::Int(1))));
+        expression = if i % 2 == 0 {
+            Expression::Op { op: Operation::Add, left, right }
+        } else {
+            Expression::Op { op: Operation::Add, left: right, right: left }
+        };
+    }
+    let program = compile(&expression);
+    let mut env = Env::new();
+    env.set("x", Value::Int(1));
+    assert_eq!(program.run(&env), Ok(Value::Int(200_001)));
+    assert_eq!(eval_with(&expression, &env), Ok(Value::Int(200_001)));
+
+    let error = program.run(&Env::new()).unwrap_err();
+    assert_eq!(error.kind, EvalErrorKind::UnboundVariable(String::from("x")));
+    assert_eq!(error.path.len(), 200_000);
+    assert_eq!(expression.at(&error.path), Some(&Expression::Var(String::from("x"))));
+}
+
+/// Compare the tree walker with the compiled program on a formula evaluated
+/// for many rows. Run with `--ignored --nocapture` to see the timings.
+#[test]
+#[ignore]
+fn bench_compiled_against_tree_walker() {
+    const ROWS: i64 = 1_000_000;
+    let source = "let d = b * b - 4 * a * c in if d < 0 then -1.0 else (-b + d ** 0.5) / (2 * a)";
+    let expression = parse_expression(source).unwrap();
+    let envs: Vec<Env> = (0..ROWS)
+        .map(|row| {
+            let mut env = Env::new();
+            env.set("a", Value::Int(row % 13 + 1));
+            env.set("b", Value::Float(row as f64 / 1000.0));
+            env.set("c", Value::Int(row % 5 - 2));
+            env
+        })
+        .collect();
+
+    let start = std::time::Instant::now();
+    let expected: Vec<_> = envs
+        .iter()
+        .map(|env| walk_with_mode(&expression, env, ArithmeticMode::Checked))
+        .collect();
+    let tree_walker = start.elapsed();
+
+    let start = std::time::Instant::now();
+    let program = compile(&expression);
+    let actual: Vec<_> = envs.iter().map(|env| program.run(env)).collect();
+    let compiled = start.elapsed();
+
+    assert_eq!(actual, expected);
+    println!("{ROWS} rows: tree walker {tree_walker:?}, compiled {compiled:?}");
+}
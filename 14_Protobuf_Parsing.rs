@@ -1,23 +1,38 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+
 /// A wire type as seen on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum WireType {
     /// The Varint WireType indicates the value is a single VARINT.
     Varint,
-    //I64,  -- not needed for this exercise
+    /// The I64 WireType indicates that the value is precisely 8 bytes in
+    /// little-endian order containing a 64-bit signed integer or double.
+    I64,
     /// The Len WireType indicates that the value is a length represented as a
     /// VARINT followed by exactly that number of bytes.
     Len,
     /// The I32 WireType indicates that the value is precisely 4 bytes in
     /// little-endian order containing a 32-bit signed integer.
     I32,
+    /// The SGroup WireType starts a (deprecated) group, whose fields follow
+    /// until a matching EGroup tag.
+    SGroup,
+    /// The EGroup WireType ends the group started by the matching SGroup.
+    EGroup,
 }
 
 #[derive(Debug)]
 /// A field's value, typed based on the wire type.
 enum FieldValue<'a> {
     Varint(u64),
-    //I64(i64),  -- not needed for this exercise
+    I64(i64),
     Len(&'a [u8]),
     I32(i32),
+    /// The encoded fields between a group's SGroup and EGroup tags.
+    Group(&'a [u8]),
 }
 
 #[derive(Debug)]
@@ -25,185 +40,5122 @@ enum FieldValue<'a> {
 struct Field<'a> {
     field_num: u64,
     value: FieldValue<'a>,
+    /// Byte offset of the value, relative to the buffer the field was parsed from.
+    offset: usize,
+    /// The complete encoding of the field, tag included.
+    raw: &'a [u8],
+}
+
+/// An error encountered while decoding wire data.
+///
+/// Every variant carries the byte offset at which the problem was found,
+/// relative to the start of the buffer handed to `parse_message`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DecodeError {
+    /// The input ended before the last byte of a VARINT.
+    TruncatedVarint { offset: usize },
+    /// A VARINT did not terminate within 10 bytes, or overflowed 64 bits.
+    OverlongVarint { offset: usize },
+    /// A tag used a wire type that is not understood.
+    UnknownWireType { offset: usize, wire_type: u64 },
+    /// A value needs more bytes than are left in the buffer.
+    LengthPastEnd { offset: usize, needed: u64, remaining: usize },
+    /// A string field did not contain valid UTF-8.
+    InvalidUtf8 { offset: usize },
+    /// A field was read with an accessor that does not match its wire type.
+    WireTypeMismatch { offset: usize, expected: WireType, found: WireType },
+    /// A VARINT does not fit in the integer type it was read as.
+    OutOfRange { offset: usize, value: u64, target: &'static str },
+    /// An EGroup tag appeared without a matching SGroup tag.
+    UnexpectedEndGroup { offset: usize, field_num: u64 },
+    /// The input ended before the EGroup tag closing a group.
+    UnterminatedGroup { offset: usize, field_num: u64 },
+    /// The input went past one of the limits set in `DecodeOptions`.
+    LimitExceeded { offset: usize, limit: DecodeLimit },
+}
+
+/// A limit from `DecodeOptions`, with the value it was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeLimit {
+    Depth(usize),
+    TotalBytes(usize),
+    RepeatedCount(usize),
+}
+
+impl DecodeError {
+    /// Shift the offset of this error by `base` bytes, used when an error from
+    /// a sub-buffer is reported in terms of the enclosing buffer.
+    fn offset_by(self, base: usize) -> Self {
+        match self {
+            DecodeError::TruncatedVarint { offset } => {
+                DecodeError::TruncatedVarint { offset: base + offset }
+            }
+            DecodeError::OverlongVarint { offset } => {
+                DecodeError::OverlongVarint { offset: base + offset }
+            }
+            DecodeError::UnknownWireType { offset, wire_type } => {
+                DecodeError::UnknownWireType { offset: base + offset, wire_type }
+            }
+            DecodeError::LengthPastEnd { offset, needed, remaining } => {
+                DecodeError::LengthPastEnd { offset: base + offset, needed, remaining }
+            }
+            DecodeError::InvalidUtf8 { offset } => {
+                DecodeError::InvalidUtf8 { offset: base + offset }
+            }
+            DecodeError::WireTypeMismatch { offset, expected, found } => {
+                DecodeError::WireTypeMismatch { offset: base + offset, expected, found }
+            }
+            DecodeError::OutOfRange { offset, value, target } => {
+                DecodeError::OutOfRange { offset: base + offset, value, target }
+            }
+            DecodeError::UnexpectedEndGroup { offset, field_num } => {
+                DecodeError::UnexpectedEndGroup { offset: base + offset, field_num }
+            }
+            DecodeError::UnterminatedGroup { offset, field_num } => {
+                DecodeError::UnterminatedGroup { offset: base + offset, field_num }
+            }
+            DecodeError::LimitExceeded { offset, limit } => {
+                DecodeError::LimitExceeded { offset: base + offset, limit }
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedVarint { offset } => {
+                write!(f, "truncated varint at byte {offset}")
+            }
+            DecodeError::OverlongVarint { offset } => {
+                write!(f, "overlong varint at byte {offset}")
+            }
+            DecodeError::UnknownWireType { offset, wire_type } => {
+                write!(f, "unknown wire type {wire_type} at byte {offset}")
+            }
+            DecodeError::LengthPastEnd { offset, needed, remaining } => write!(
+                f,
+                "value at byte {offset} needs {needed} bytes but only {remaining} remain"
+            ),
+            DecodeError::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 at byte {offset}")
+            }
+            DecodeError::WireTypeMismatch { offset, expected, found } => write!(
+                f,
+                "expected a {expected:?} field but found {found:?} at byte {offset}"
+            ),
+            DecodeError::OutOfRange { offset, value, target } => {
+                write!(f, "value {value} at byte {offset} does not fit in {target}")
+            }
+            DecodeError::UnexpectedEndGroup { offset, field_num } => {
+                write!(f, "unexpected end of group {field_num} at byte {offset}")
+            }
+            DecodeError::UnterminatedGroup { offset, field_num } => {
+                write!(f, "group {field_num} starting at byte {offset} is never closed")
+            }
+            DecodeError::LimitExceeded { offset, limit } => match limit {
+                DecodeLimit::Depth(max) => {
+                    write!(f, "message at byte {offset} is nested more than {max} deep")
+                }
+                DecodeLimit::TotalBytes(max) => {
+                    write!(f, "message at byte {offset} is larger than {max} bytes")
+                }
+                DecodeLimit::RepeatedCount(max) => {
+                    write!(f, "repeated field at byte {offset} has more than {max} elements")
+                }
+            },
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Limits that keep crafted input from exhausting the stack or memory while
+/// decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DecodeOptions {
+    /// How deeply messages may be nested inside the top-level message.
+    max_depth: usize,
+    /// The largest message accepted, in bytes.
+    max_total_bytes: usize,
+    /// The most elements a single repeated or map field may hold.
+    max_repeated: usize,
+}
+
+impl Default for DecodeOptions {
+    /// The same depth limit as the reference implementation, and a 64 MiB size
+    /// limit. Repeated fields are only limited by the size of the input.
+    fn default() -> Self {
+        DecodeOptions { max_depth: 100, max_total_bytes: 64 << 20, max_repeated: usize::MAX }
+    }
+}
+
+impl DecodeOptions {
+    fn max_depth(mut self, max: usize) -> Self {
+        self.max_depth = max;
+        self
+    }
+
+    fn max_total_bytes(mut self, max: usize) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    fn max_repeated(mut self, max: usize) -> Self {
+        self.max_repeated = max;
+        self
+    }
+
+    /// The depth of a message nested in one at `depth`, if it is within limits.
+    fn nested(&self, depth: usize) -> Result<usize, DecodeError> {
+        if depth >= self.max_depth {
+            let limit = DecodeLimit::Depth(self.max_depth);
+            return Err(DecodeError::LimitExceeded { offset: 0, limit });
+        }
+        Ok(depth + 1)
+    }
+
+    /// Check that a repeated field holding `count` elements is within limits.
+    fn check_repeated(&self, count: usize) -> Result<(), DecodeError> {
+        if count > self.max_repeated {
+            let limit = DecodeLimit::RepeatedCount(self.max_repeated);
+            return Err(DecodeError::LimitExceeded { offset: 0, limit });
+        }
+        Ok(())
+    }
 }
 
 trait ProtoMessage<'a>: Default + 'a {
-    fn add_field(&mut self, field: Field<'a>);
+    /// Add a decoded field to a message nested `depth` levels deep, decoding
+    /// any message inside it at `options.nested(depth)`.
+    ///
+    /// Error offsets are relative to the start of the field's value;
+    /// `parse_message` turns them into offsets within the whole message.
+    fn add_field(
+        &mut self,
+        field: Field<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError>;
+
+    /// Merge `other` into this message, giving the same result as decoding
+    /// the encoding of this message followed by that of `other`. Fields set in
+    /// `other` replace singular fields, are appended to repeated fields, and
+    /// are merged into message fields and maps.
+    fn merge_from(&mut self, other: Self);
+}
+
+/// The encoding counterpart of `ProtoMessage`.
+trait ProtoEncode {
+    /// Append the encoded fields of this message to `out`.
+    fn encode_fields(&self, out: &mut Vec<u8>);
+}
+
+// Boxing lets a message contain a message of its own type.
+
+impl<'a, T: ProtoMessage<'a>> ProtoMessage<'a> for Box<T> {
+    fn add_field(
+        &mut self,
+        field: Field<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError> {
+        (**self).add_field(field, options, depth)
+    }
+
+    fn merge_from(&mut self, other: Self) {
+        (**self).merge_from(*other);
+    }
+}
+
+impl<T: ProtoEncode> ProtoEncode for Box<T> {
+    fn encode_fields(&self, out: &mut Vec<u8>) {
+        (**self).encode_fields(out);
+    }
+}
+
+impl<T: ProtoJson> ProtoJson for Box<T> {
+    fn to_json(&self) -> JsonValue {
+        (**self).to_json()
+    }
+
+    fn json_to_wire(value: &JsonValue, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        T::json_to_wire(value, out)
+    }
+}
+
+impl<T: ProtoText> ProtoText for Box<T> {
+    fn to_text(&self) -> Vec<(String, TextValue)> {
+        (**self).to_text()
+    }
+
+    fn text_to_wire(fields: &[(String, TextValue)], out: &mut Vec<u8>) -> Result<(), TextError> {
+        T::text_to_wire(fields, out)
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Box<T> {
+    type Owned = Box<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        Box::new((*self).into_owned())
+    }
 }
 
-impl From<u64> for WireType {
-    fn from(value: u64) -> Self {
+impl TryFrom<u64> for WireType {
+    type Error = DecodeError;
+
+    fn try_from(value: u64) -> Result<Self, DecodeError> {
         match value {
-            0 => WireType::Varint,
-            //1 => WireType::I64,  -- not needed for this exercise
-            2 => WireType::Len,
-            5 => WireType::I32,
-            _ => panic!("Invalid wire type: {value}"),
+            0 => Ok(WireType::Varint),
+            1 => Ok(WireType::I64),
+            2 => Ok(WireType::Len),
+            3 => Ok(WireType::SGroup),
+            4 => Ok(WireType::EGroup),
+            5 => Ok(WireType::I32),
+            _ => Err(DecodeError::UnknownWireType { offset: 0, wire_type: value }),
         }
     }
 }
 
 impl<'a> FieldValue<'a> {
-    fn as_string(&self) -> &'a str {
-        let FieldValue::Len(data) = self else {
-            panic!("Expected string to be a `Len` field");
-        };
-        std::str::from_utf8(data).expect("Invalid string")
+    fn wire_type(&self) -> WireType {
+        match self {
+            FieldValue::Varint(_) => WireType::Varint,
+            FieldValue::I64(_) => WireType::I64,
+            FieldValue::Len(_) => WireType::Len,
+            FieldValue::I32(_) => WireType::I32,
+            FieldValue::Group(_) => WireType::SGroup,
+        }
+    }
+
+    fn mismatch(&self, expected: WireType) -> DecodeError {
+        DecodeError::WireTypeMismatch { offset: 0, expected, found: self.wire_type() }
+    }
+
+    fn as_string(&self) -> Result<&'a str, DecodeError> {
+        let data = self.as_bytes()?;
+        std::str::from_utf8(data)
+            .map_err(|e| DecodeError::InvalidUtf8 { offset: e.valid_up_to() })
     }
 
-    fn as_bytes(&self) -> &'a [u8] {
+    fn as_bytes(&self) -> Result<&'a [u8], DecodeError> {
         let FieldValue::Len(data) = self else {
-            panic!("Expected bytes to be a `Len` field");
+            return Err(self.mismatch(WireType::Len));
         };
-        data
+        Ok(data)
     }
 
-    fn as_u64(&self) -> u64 {
+    fn as_u64(&self) -> Result<u64, DecodeError> {
         let FieldValue::Varint(value) = self else {
-            panic!("Expected `u64` to be a `Varint` field");
+            return Err(self.mismatch(WireType::Varint));
         };
-        *value
+        Ok(*value)
+    }
+
+    fn out_of_range(value: u64, target: &'static str) -> DecodeError {
+        DecodeError::OutOfRange { offset: 0, value, target }
+    }
+
+    /// Read a `uint32` field, rejecting values above `u32::MAX`.
+    fn as_u32(&self) -> Result<u32, DecodeError> {
+        let value = self.as_u64()?;
+        u32::try_from(value).map_err(|_| Self::out_of_range(value, "uint32"))
+    }
+
+    /// Read an `int64` field. Negative values are sent as their two's
+    /// complement, so they always take 10 bytes on the wire.
+    fn as_i64(&self) -> Result<i64, DecodeError> {
+        Ok(self.as_u64()? as i64)
+    }
+
+    /// Read an `int32` field, which is sign-extended to 64 bits on the wire.
+    fn as_int32(&self) -> Result<i32, DecodeError> {
+        let value = self.as_u64()?;
+        i32::try_from(value as i64).map_err(|_| Self::out_of_range(value, "int32"))
+    }
+
+    /// Read an `enum` field, which is encoded like `int32`.
+    fn as_enum<E: From<i32>>(&self) -> Result<E, DecodeError> {
+        self.as_int32().map(E::from)
+    }
+
+    /// Read a ZigZag-encoded `sint64` field.
+    fn as_sint64(&self) -> Result<i64, DecodeError> {
+        Ok(zigzag_decode(self.as_u64()?))
+    }
+
+    /// Read a ZigZag-encoded `sint32` field.
+    fn as_sint32(&self) -> Result<i32, DecodeError> {
+        let value = self.as_u64()?;
+        if value > u32::MAX as u64 {
+            return Err(Self::out_of_range(value, "sint32"));
+        }
+        Ok(zigzag_decode(value) as i32)
     }
 
-    #[allow(dead_code)]
-    fn as_i32(&self) -> i32 {
+    /// Read a `bool` field. Only 0 and 1 are accepted.
+    fn as_bool(&self) -> Result<bool, DecodeError> {
+        match self.as_u64()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(Self::out_of_range(value, "bool")),
+        }
+    }
+
+    /// Read a `sfixed32` field.
+    fn as_i32(&self) -> Result<i32, DecodeError> {
         let FieldValue::I32(value) = self else {
-            panic!("Expected `i32` to be an `I32` field");
+            return Err(self.mismatch(WireType::I32));
+        };
+        Ok(*value)
+    }
+
+    /// Read a `fixed32` field.
+    fn as_fixed32(&self) -> Result<u32, DecodeError> {
+        Ok(self.as_i32()? as u32)
+    }
+
+    /// Read a `float` field.
+    fn as_f32(&self) -> Result<f32, DecodeError> {
+        Ok(f32::from_bits(self.as_fixed32()?))
+    }
+
+    /// Read a `sfixed64` field.
+    fn as_sfixed64(&self) -> Result<i64, DecodeError> {
+        let FieldValue::I64(value) = self else {
+            return Err(self.mismatch(WireType::I64));
         };
-        *value
+        Ok(*value)
+    }
+
+    /// Read a `fixed64` field.
+    fn as_fixed64(&self) -> Result<u64, DecodeError> {
+        Ok(self.as_sfixed64()? as u64)
+    }
+
+    /// Read a `double` field.
+    fn as_f64(&self) -> Result<f64, DecodeError> {
+        Ok(f64::from_bits(self.as_fixed64()?))
+    }
+
+    /// Iterate over the values of a repeated VARINT field. Both the packed
+    /// form, a `Len` field of concatenated VARINTs, and a single unpacked
+    /// value are accepted.
+    fn packed_varints(&self) -> Result<Packed<'a, u64>, DecodeError> {
+        match self {
+            FieldValue::Len(data) => Ok(Packed::new(data, parse_varint)),
+            FieldValue::Varint(value) => Ok(Packed::single(*value)),
+            _ => Err(self.mismatch(WireType::Len)),
+        }
+    }
+
+    /// Iterate over the values of a repeated 32-bit fixed-width field, packed
+    /// or not.
+    fn packed_fixed32(&self) -> Result<Packed<'a, u32>, DecodeError> {
+        match self {
+            FieldValue::Len(data) => Ok(Packed::new(data, parse_fixed32)),
+            FieldValue::I32(value) => Ok(Packed::single(*value as u32)),
+            _ => Err(self.mismatch(WireType::Len)),
+        }
+    }
+
+    /// Iterate over the values of a repeated 64-bit fixed-width field, packed
+    /// or not.
+    fn packed_fixed64(&self) -> Result<Packed<'a, u64>, DecodeError> {
+        match self {
+            FieldValue::Len(data) => Ok(Packed::new(data, parse_fixed64)),
+            FieldValue::I64(value) => Ok(Packed::single(*value as u64)),
+            _ => Err(self.mismatch(WireType::Len)),
+        }
+    }
+}
+
+/// A function reading one value from the front of a buffer, like `parse_varint`.
+type ParseFn<T> = fn(&[u8]) -> Result<(T, &[u8]), DecodeError>;
+
+/// An iterator over the values of a packed repeated field, reading them
+/// directly from the message buffer.
+///
+/// Error offsets are relative to the start of the packed data. After an error
+/// the iterator is exhausted.
+struct Packed<'a, T> {
+    data: &'a [u8],
+    offset: usize,
+    parse: ParseFn<T>,
+    /// The value of an unpacked field, which is yielded on its own.
+    single: Option<T>,
+}
+
+impl<'a, T> Packed<'a, T> {
+    fn new(data: &'a [u8], parse: ParseFn<T>) -> Self {
+        Packed { data, offset: 0, parse, single: None }
+    }
+
+    fn single(value: T) -> Self {
+        Packed { data: &[], offset: 0, parse: |_| unreachable!(), single: Some(value) }
+    }
+}
+
+impl<T> Iterator for Packed<'_, T> {
+    type Item = Result<T, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(value) = self.single.take() {
+            return Some(Ok(value));
+        }
+        if self.data.is_empty() {
+            return None;
+        }
+        match (self.parse)(self.data) {
+            Ok((value, remainder)) => {
+                self.offset += self.data.len() - remainder.len();
+                self.data = remainder;
+                Some(Ok(value))
+            }
+            Err(err) => {
+                self.data = &[];
+                Some(Err(err.offset_by(self.offset)))
+            }
+        }
     }
 }
 
 /// Parse a VARINT, returning the parsed value and the remaining bytes.
-fn parse_varint(data: &[u8]) -> (u64, &[u8]) {
-    for i in 0..7 {
-        let Some(b) = data.get(i) else {
-            panic!("Not enough bytes for varint");
+fn parse_varint(data: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let Some(&b) = data.get(i) else {
+            return Err(DecodeError::TruncatedVarint { offset: 0 });
         };
+        // The tenth byte only has room for the top bit of a u64.
+        if i == 9 && b > 0x01 {
+            return Err(DecodeError::OverlongVarint { offset: 0 });
+        }
+        // VARINTs are little-endian groups of 7 bits.
+        value |= ((b & 0x7f) as u64) << (7 * i);
         if b & 0x80 == 0 {
-            // This is the last byte of the VARINT, so convert it to
-            // a u64 and return it.
-            let mut value = 0u64;
-            for b in data[..=i].iter().rev() {
-                value = (value << 7) | (b & 0x7f) as u64;
-            }
-            return (value, &data[i + 1..]);
+            return Ok((value, &data[i + 1..]));
         }
     }
 
-    // More than 7 bytes is invalid.
-    panic!("Too many bytes for varint");
+    // More than 10 bytes is invalid.
+    Err(DecodeError::OverlongVarint { offset: 0 })
+}
+
+/// Parse a little-endian 32-bit value, returning it and the remaining bytes.
+fn parse_fixed32(data: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    let (value, remainder) = take_bytes(data, 4)?;
+    let mut buf: [u8; 4] = [0; 4];
+    buf.copy_from_slice(value);
+    Ok((u32::from_le_bytes(buf), remainder))
+}
+
+/// Parse a little-endian 64-bit value, returning it and the remaining bytes.
+fn parse_fixed64(data: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+    let (value, remainder) = take_bytes(data, 8)?;
+    let mut buf: [u8; 8] = [0; 8];
+    buf.copy_from_slice(value);
+    Ok((u64::from_le_bytes(buf), remainder))
+}
+
+/// Undo the ZigZag encoding used by `sint32` and `sint64`, which maps signed
+/// integers to unsigned ones so that small negative numbers stay short.
+fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
 }
 
 /// Convert a tag into a field number and a WireType.
-fn unpack_tag(tag: u64) -> (u64, WireType) {
+fn unpack_tag(tag: u64) -> Result<(u64, WireType), DecodeError> {
     let field_num = tag >> 3;
-    let wire_type = WireType::from(tag & 0x7);
-    (field_num, wire_type)
+    let wire_type = WireType::try_from(tag & 0x7)?;
+    Ok((field_num, wire_type))
+}
+
+/// Split `len` bytes off the front of `data`, or report how far short it is.
+fn take_bytes(data: &[u8], len: u64) -> Result<(&[u8], &[u8]), DecodeError> {
+    if len > data.len() as u64 {
+        return Err(DecodeError::LengthPastEnd {
+            offset: 0,
+            needed: len,
+            remaining: data.len(),
+        });
+    }
+    Ok(data.split_at(len as usize))
 }
 
+/// Skip the fields of a group with the given field number, returning the group
+/// body and the bytes following its EGroup tag.
+///
+/// Groups nested inside the group are tracked on a stack rather than by
+/// recursion, so deeply nested input cannot overflow the call stack.
+fn skip_group(data: &[u8], field_num: u64) -> Result<(&[u8], &[u8]), DecodeError> {
+    let mut open = vec![field_num];
+    let mut rest = data;
+    loop {
+        if rest.is_empty() {
+            return Err(DecodeError::UnterminatedGroup { offset: 0, field_num });
+        }
+        let offset = data.len() - rest.len();
+        let (tag, after_tag) = parse_varint(rest).map_err(|e| e.offset_by(offset))?;
+        let (inner_num, wire_type) = unpack_tag(tag).map_err(|e| e.offset_by(offset))?;
+        match wire_type {
+            WireType::SGroup => {
+                open.push(inner_num);
+                rest = after_tag;
+            }
+            WireType::EGroup => {
+                if open.pop() != Some(inner_num) {
+                    return Err(DecodeError::UnexpectedEndGroup { offset, field_num: inner_num });
+                }
+                if open.is_empty() {
+                    return Ok((&data[..offset], after_tag));
+                }
+                rest = after_tag;
+            }
+            _ => {
+                let (_, remainder) = parse_field(rest).map_err(|e| e.offset_by(offset))?;
+                rest = remainder;
+            }
+        }
+    }
+}
 
 /// Parse a field, returning the remaining bytes
-fn parse_field(data: &[u8]) -> (Field, &[u8]) {
-    let (tag, remainder) = parse_varint(data);
-    let (field_num, wire_type) = unpack_tag(tag);
-    let (fieldvalue, remainder) = match wire_type {
-    
+fn parse_field(data: &[u8]) -> Result<(Field<'_>, &[u8]), DecodeError> {
+    // Offset of `rest` within `data`, used to position errors.
+    let pos = |rest: &[u8]| data.len() - rest.len();
+
+    let (tag, remainder) = parse_varint(data)?;
+    let (field_num, wire_type) = unpack_tag(tag)?;
+    let offset = pos(remainder);
+    let (fieldvalue, offset, remainder) = match wire_type {
+
         // Based on the wire type, build a Field, consuming as many bytes as necessary
-        
+
         WireType::Varint => {
-            let (varint_data, remainder) = parse_varint(remainder);
-            (FieldValue::Varint(varint_data), remainder)
+            let (varint_data, remainder) =
+                parse_varint(remainder).map_err(|e| e.offset_by(offset))?;
+            (FieldValue::Varint(varint_data), offset, remainder)
         },
-        
+
         // The Len WireType indicates that the value is a length represented as a
         // VARINT followed by exactly that number of bytes.
         WireType::Len => {
-            let (len_size, remainder) = parse_varint(remainder);
-            (FieldValue::Len(&remainder[0..len_size as usize]), &remainder[len_size as usize..])
+            let (len_size, remainder) =
+                parse_varint(remainder).map_err(|e| e.offset_by(offset))?;
+            let offset = pos(remainder);
+            let (value, remainder) =
+                take_bytes(remainder, len_size).map_err(|e| e.offset_by(offset))?;
+            (FieldValue::Len(value), offset, remainder)
+        }
+
+        WireType::I64 => {
+            let (value, remainder) =
+                parse_fixed64(remainder).map_err(|e| e.offset_by(offset))?;
+            (FieldValue::I64(value as i64), offset, remainder)
+        }
+
+        // Groups are delimited by a matching EGroup tag rather than a length, so
+        // skip over the enclosed fields to find where the group ends.
+        WireType::SGroup => {
+            let (body, remainder) =
+                skip_group(remainder, field_num).map_err(|e| e.offset_by(offset))?;
+            (FieldValue::Group(body), offset, remainder)
         }
-        
+
+        WireType::EGroup => {
+            return Err(DecodeError::UnexpectedEndGroup { offset: 0, field_num });
+        }
+
         WireType::I32 => {
-            // Copy 4 Bytes into a buffer of 4 bytes size
-            let mut buf: [u8; 4] = [0; 4];
-            buf[0..4].copy_from_slice(&remainder[0..4]);
-            // Copy now the content stored in buffer to the i32 type
-            (FieldValue::I32(i32::from_be_bytes(buf)), &remainder[4..])
+            let (value, remainder) =
+                parse_fixed32(remainder).map_err(|e| e.offset_by(offset))?;
+            (FieldValue::I32(value as i32), offset, remainder)
         }
     };
-    
+
     // Return the field, and any un-consumed bytes
-    (Field{
-        field_num: field_num,
-        value: fieldvalue
-    }, remainder)
+    Ok((Field {
+        field_num,
+        value: fieldvalue,
+        offset,
+        raw: &data[..pos(remainder)],
+    }, remainder))
 }
 
 /// Parse a message in the given data, calling `T::add_field` for each field in
 /// the message.
 ///
-/// The entire input is consumed.
-fn parse_message<'a, T: ProtoMessage<'a>>(mut data: &'a [u8]) -> T {
+/// The entire input is consumed. Any error carries the byte offset within
+/// `data` at which decoding failed. The default `DecodeOptions` apply.
+fn parse_message<'a, T: ProtoMessage<'a>>(data: &'a [u8]) -> Result<T, DecodeError> {
+    parse_message_with(data, &DecodeOptions::default())
+}
+
+/// Parse a message like `parse_message`, within the given limits.
+fn parse_message_with<'a, T: ProtoMessage<'a>>(
+    data: &'a [u8],
+    options: &DecodeOptions,
+) -> Result<T, DecodeError> {
     let mut result = T::default();
+    merge_message_with(&mut result, data, options)?;
+    Ok(result)
+}
+
+/// Parse the fields in the given data into an existing message, as if they had
+/// been appended to the bytes it was decoded from.
+fn merge_message<'a, T: ProtoMessage<'a>>(
+    message: &mut T,
+    data: &'a [u8],
+) -> Result<(), DecodeError> {
+    merge_message_with(message, data, &DecodeOptions::default())
+}
+
+/// Merge fields into a message like `merge_message`, within the given limits.
+fn merge_message_with<'a, T: ProtoMessage<'a>>(
+    message: &mut T,
+    data: &'a [u8],
+    options: &DecodeOptions,
+) -> Result<(), DecodeError> {
+    merge_message_at(message, data, options, 0)
+}
+
+/// Merge fields into a message that is nested `depth` levels deep.
+fn merge_message_at<'a, T: ProtoMessage<'a>>(
+    message: &mut T,
+    mut data: &'a [u8],
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<(), DecodeError> {
+    if data.len() > options.max_total_bytes {
+        let limit = DecodeLimit::TotalBytes(options.max_total_bytes);
+        return Err(DecodeError::LimitExceeded { offset: 0, limit });
+    }
+    let mut base = 0;
     while !data.is_empty() {
-        let parsed = parse_field(data);
-        result.add_field(parsed.0);
-        data = parsed.1;
+        let (field, remainder) = parse_field(data).map_err(|e| e.offset_by(base))?;
+        let value_offset = base + field.offset;
+        message.add_field(field, options, depth).map_err(|e| e.offset_by(value_offset))?;
+        base += data.len() - remainder.len();
+        data = remainder;
     }
-    result
+    Ok(())
 }
 
-#[derive(Debug, Default)]
-struct PhoneNumber<'a> {
-    number: &'a str,
-    type_: &'a str,
+/// Append `value` to `out` as a VARINT.
+fn encode_varint(mut value: u64, out: &mut Vec<u8>) {
+    // Emit 7 bits at a time, setting the top bit on every byte but the last.
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
 }
 
-#[derive(Debug, Default)]
-struct Person<'a> {
-    name: &'a str,
-    id: u64,
-    phone: Vec<PhoneNumber<'a>>,
+/// The inverse of `zigzag_decode`, for writing `sint32` and `sint64` values.
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
 }
 
-// Implement ProtoMessage for Person and PhoneNumber
+/// Convert a field number and a WireType into a tag.
+fn pack_tag(field_num: u64, wire_type: WireType) -> u64 {
+    let wire_type = match wire_type {
+        WireType::Varint => 0,
+        WireType::I64 => 1,
+        WireType::Len => 2,
+        WireType::SGroup => 3,
+        WireType::EGroup => 4,
+        WireType::I32 => 5,
+    };
+    (field_num << 3) | wire_type
+}
 
-impl<'a> ProtoMessage<'a> for Person<'a> {
-    fn add_field(&mut self, field: Field<'a>){
-        
-        match field.field_num {
-            1 => self.name = field.value.as_string(),
-            2 => self.id = field.value.as_u64(),
-            3 => {
-                // Extract the bytes and deserialize them into PhoneNumber struct
-                let phone_number: PhoneNumber = parse_message(field.value.as_bytes());
-                // Add the phone number to the vector 
-                self.phone.push(phone_number)
-            },
-            _ => ()
+/// Append a field with the given number and value to `out`.
+fn encode_field(field_num: u64, value: &FieldValue, out: &mut Vec<u8>) {
+    encode_varint(pack_tag(field_num, value.wire_type()), out);
+    match value {
+        FieldValue::Len(data) => {
+            encode_varint(data.len() as u64, out);
+            out.extend_from_slice(data);
         }
+        FieldValue::Group(data) => {
+            out.extend_from_slice(data);
+            encode_varint(pack_tag(field_num, WireType::EGroup), out);
+        }
+        _ => encode_scalar(value, out),
     }
 }
 
-impl<'a> ProtoMessage<'a> for PhoneNumber<'a> {
-    fn add_field(&mut self, field: Field<'a>){
-        
-        match field.field_num {
-            1 => self.number = field.value.as_string(),
-            2 => self.type_ = field.value.as_string(),
-            _ => ()
+/// Append a Varint, I64 or I32 value to `out` without a tag, as is done for
+/// each element of a packed repeated field.
+fn encode_scalar(value: &FieldValue, out: &mut Vec<u8>) {
+    match value {
+        FieldValue::Varint(value) => encode_varint(*value, out),
+        FieldValue::I64(value) => out.extend_from_slice(&value.to_le_bytes()),
+        FieldValue::I32(value) => out.extend_from_slice(&value.to_le_bytes()),
+        FieldValue::Len(_) | FieldValue::Group(_) => {
+            unreachable!("{:?} values are not scalars", value.wire_type())
         }
     }
 }
 
-fn main() {
-    let person: Person = parse_message(&[
-        0x0a, 0x07, 0x6d, 0x61, 0x78, 0x77, 0x65, 0x6c, 0x6c, 0x10, 0x2a, 0x1a,
-        0x16, 0x0a, 0x0e, 0x2b, 0x31, 0x32, 0x30, 0x32, 0x2d, 0x35, 0x35, 0x35,
-        0x2d, 0x31, 0x32, 0x31, 0x32, 0x12, 0x04, 0x68, 0x6f, 0x6d, 0x65, 0x1a,
-        0x18, 0x0a, 0x0e, 0x2b, 0x31, 0x38, 0x30, 0x30, 0x2d, 0x38, 0x36, 0x37,
-        0x2d, 0x35, 0x33, 0x30, 0x38, 0x12, 0x06, 0x6d, 0x6f, 0x62, 0x69, 0x6c,
-        0x65,
-    ]);
-    println!("{:#?}", person);
+/// Encode a message, producing bytes that `parse_message` can read back.
+fn encode<T: ProtoEncode>(message: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    message.encode_fields(&mut out);
+    out
+}
+
+/// A Rust type that can be stored in a field of a `proto_message!` struct.
+trait ProtoField<'a> {
+    /// Merge a decoded value into the field of a message nested `depth` levels
+    /// deep. Singular fields are overwritten, as the last occurrence wins,
+    /// while repeated fields are appended to.
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError>;
+
+    /// Merge another value of the field into this one, as if it had been
+    /// decoded afterwards. It is only called when `other` is not the default,
+    /// so by default `other` simply replaces the current value.
+    fn merge_other(&mut self, other: Self)
+    where
+        Self: Sized,
+    {
+        *self = other;
+    }
+
+    /// Whether the field holds its default value, and can be left out.
+    fn is_default(&self) -> bool;
+
+    /// Append the field with the given number to `out`.
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>);
+
+    /// Merge one occurrence of a repeated field of this type into `values`.
+    /// Scalars override this to also accept the packed encoding.
+    fn merge_repeated(
+        values: &mut Vec<Self>,
+        value: &FieldValue<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized + Default,
+    {
+        let mut item = Self::default();
+        item.merge_value(value, options, depth)?;
+        values.push(item);
+        Ok(())
+    }
+
+    /// Append a repeated field of this type to `out`. Scalars override this to
+    /// use the packed encoding, as proto3 does by default.
+    fn encode_repeated(values: &[Self], field_num: u64, out: &mut Vec<u8>)
+    where
+        Self: Sized,
+    {
+        for value in values {
+            value.encode_value(field_num, out);
+        }
+    }
+
+    /// The proto3 JSON representation of the field.
+    fn to_json_value(&self) -> JsonValue;
+
+    /// Append the field described by a JSON value to `out` as wire data.
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError>
+    where
+        Self: Sized;
+
+    /// Append the elements of a JSON array for a repeated field of this type
+    /// to `out` as wire data.
+    fn encode_json_repeated(
+        items: &[JsonValue],
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError>
+    where
+        Self: Sized,
+    {
+        for item in items {
+            Self::encode_json_value(item, field_num, out)?;
+        }
+        Ok(())
+    }
+
+    /// Append the field's text format values to `fields`, one per occurrence.
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>);
+
+    /// Append one occurrence of the field, given in text format, to `out` as
+    /// wire data.
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError>
+    where
+        Self: Sized;
+}
+
+/// Merge the values of a packed repeated scalar field into `values`, where
+/// `wire_type` is the wire type each value would have if sent unpacked.
+fn merge_packed<'a, T: ProtoField<'a> + Default>(
+    values: &mut Vec<T>,
+    data: &'a [u8],
+    wire_type: WireType,
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<(), DecodeError> {
+    let mut push = |value: FieldValue<'a>| -> Result<(), DecodeError> {
+        // Check as we go, so a huge packed field is rejected before it is
+        // all decoded.
+        options.check_repeated(values.len() + 1)?;
+        let mut item = T::default();
+        item.merge_value(&value, options, depth)?;
+        values.push(item);
+        Ok(())
+    };
+    let packed = FieldValue::Len(data);
+    match wire_type {
+        WireType::Varint => {
+            for value in packed.packed_varints()? {
+                push(FieldValue::Varint(value?))?;
+            }
+        }
+        WireType::I32 => {
+            for value in packed.packed_fixed32()? {
+                push(FieldValue::I32(value? as i32))?;
+            }
+        }
+        WireType::I64 => {
+            for value in packed.packed_fixed64()? {
+                push(FieldValue::I64(value? as i64))?;
+            }
+        }
+        _ => unreachable!("{wire_type:?} values cannot be packed"),
+    }
+    Ok(())
+}
+
+/// Conversion of a decoded value into one that no longer borrows from the
+/// input, so that it can outlive the buffer or be sent to another thread.
+/// `proto_message!` implements it for messages, so `Person<'a>` becomes a
+/// `Person<'static>`.
+trait IntoOwned {
+    type Owned: 'static;
+
+    fn into_owned(self) -> Self::Owned;
+}
+
+// Strings and bytes are borrowed while decoding, and copied only when the
+// message is made owned.
+impl<B: ?Sized + ToOwned + 'static> IntoOwned for Cow<'_, B> {
+    type Owned = Cow<'static, B>;
+
+    fn into_owned(self) -> Cow<'static, B> {
+        Cow::Owned(Cow::into_owned(self))
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Option<T> {
+    type Owned = Option<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.map(T::into_owned)
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Vec<T> {
+    type Owned = Vec<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.into_iter().map(T::into_owned).collect()
+    }
+}
+
+/// Implement `ProtoField` for a `Copy` scalar, given the accessor used to read
+/// it and a conversion into a `FieldValue`. Newtypes such as `Sint32` are
+/// written as `Sint32(i32)`, with the accessor and conversion working on the
+/// wrapped type.
+macro_rules! scalar_field {
+    (@impl $ty:ty, $inner:ty, $wrap:expr, $unwrap:expr, $as:ident, $to_wire:expr) => {
+        impl<'a> ProtoField<'a> for $ty {
+            fn merge_value(
+                &mut self,
+                value: &FieldValue<'a>,
+                _options: &DecodeOptions,
+                _depth: usize,
+            ) -> Result<(), DecodeError> {
+                let wrap: fn($inner) -> $ty = $wrap;
+                *self = wrap(value.$as()?);
+                Ok(())
+            }
+
+            fn is_default(&self) -> bool {
+                *self == <$ty>::default()
+            }
+
+            fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+                let unwrap: fn($ty) -> $inner = $unwrap;
+                let to_wire: fn($inner) -> FieldValue<'static> = $to_wire;
+                encode_field(field_num, &to_wire(unwrap(*self)), out);
+            }
+
+            fn merge_repeated(
+                values: &mut Vec<Self>,
+                value: &FieldValue<'a>,
+                options: &DecodeOptions,
+                depth: usize,
+            ) -> Result<(), DecodeError> {
+                let FieldValue::Len(data) = value else {
+                    let mut item = Self::default();
+                    item.merge_value(value, options, depth)?;
+                    values.push(item);
+                    return Ok(());
+                };
+                let unwrap: fn($ty) -> $inner = $unwrap;
+                let to_wire: fn($inner) -> FieldValue<'static> = $to_wire;
+                let wire_type = to_wire(unwrap(Self::default())).wire_type();
+                merge_packed(values, data, wire_type, options, depth)
+            }
+
+            fn encode_repeated(values: &[Self], field_num: u64, out: &mut Vec<u8>) {
+                let unwrap: fn($ty) -> $inner = $unwrap;
+                let to_wire: fn($inner) -> FieldValue<'static> = $to_wire;
+                let mut packed = Vec::new();
+                for value in values {
+                    encode_scalar(&to_wire(unwrap(*value)), &mut packed);
+                }
+                encode_field(field_num, &FieldValue::Len(&packed), out);
+            }
+
+            fn to_json_value(&self) -> JsonValue {
+                let unwrap: fn($ty) -> $inner = $unwrap;
+                unwrap(*self).to_json()
+            }
+
+            fn encode_json_value(
+                value: &JsonValue,
+                field_num: u64,
+                out: &mut Vec<u8>,
+            ) -> Result<(), JsonError> {
+                let to_wire: fn($inner) -> FieldValue<'static> = $to_wire;
+                encode_field(field_num, &to_wire(<$inner>::from_json(value)?), out);
+                Ok(())
+            }
+
+            fn encode_json_repeated(
+                items: &[JsonValue],
+                field_num: u64,
+                out: &mut Vec<u8>,
+            ) -> Result<(), JsonError> {
+                let wrap: fn($inner) -> $ty = $wrap;
+                let values = items
+                    .iter()
+                    .map(|item| Ok(wrap(<$inner>::from_json(item)?)))
+                    .collect::<Result<Vec<_>, JsonError>>()?;
+                Self::encode_repeated(&values, field_num, out);
+                Ok(())
+            }
+
+            fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+                let unwrap: fn($ty) -> $inner = $unwrap;
+                let token = text_scalar(unwrap(*self).to_json());
+                fields.push((name.to_string(), TextValue::Scalar(token)));
+            }
+
+            fn encode_text_value(
+                value: &TextValue,
+                field_num: u64,
+                out: &mut Vec<u8>,
+            ) -> Result<(), TextError> {
+                let TextValue::Scalar(token) = value else {
+                    return Err(TextError::invalid(stringify!($inner)));
+                };
+                let value = <$inner>::from_json(&text_scalar_to_json(token))
+                    .map_err(|_| TextError::invalid(stringify!($inner)))?;
+                let to_wire: fn($inner) -> FieldValue<'static> = $to_wire;
+                encode_field(field_num, &to_wire(value), out);
+                Ok(())
+            }
+        }
+
+        impl IntoOwned for $ty {
+            type Owned = $ty;
+
+            fn into_owned(self) -> $ty {
+                self
+            }
+        }
+    };
+    ($wrapper:ident($ty:ty), $as:ident, $to_wire:expr) => {
+        scalar_field!(@impl $wrapper, $ty, $wrapper, |v: $wrapper| v.0, $as, $to_wire);
+    };
+    ($ty:ty, $as:ident, $to_wire:expr) => {
+        scalar_field!(
+            @impl $ty, $ty, std::convert::identity, std::convert::identity, $as, $to_wire
+        );
+    };
+}
+
+scalar_field!(u64, as_u64, FieldValue::Varint);
+scalar_field!(u32, as_u32, |v| FieldValue::Varint(v as u64));
+scalar_field!(i64, as_i64, |v| FieldValue::Varint(v as u64));
+scalar_field!(i32, as_int32, |v| FieldValue::Varint(v as i64 as u64));
+scalar_field!(bool, as_bool, |v| FieldValue::Varint(v as u64));
+scalar_field!(f64, as_f64, |v| FieldValue::I64(v.to_bits() as i64));
+scalar_field!(f32, as_f32, |v| FieldValue::I32(v.to_bits() as i32));
+
+// Integer types whose wire encoding differs from the plain VARINT used for the
+// Rust type they wrap.
+
+/// A `sint32` value, ZigZag-encoded on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Sint32(i32);
+
+/// A `sint64` value, ZigZag-encoded on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Sint64(i64);
+
+/// A `fixed32` value, always 4 bytes on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Fixed32(u32);
+
+/// A `fixed64` value, always 8 bytes on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Fixed64(u64);
+
+/// A `sfixed32` value, always 4 bytes on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Sfixed32(i32);
+
+/// A `sfixed64` value, always 8 bytes on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Sfixed64(i64);
+
+scalar_field!(Sint32(i32), as_sint32, |v| FieldValue::Varint(zigzag_encode(v as i64)));
+scalar_field!(Sint64(i64), as_sint64, |v| FieldValue::Varint(zigzag_encode(v)));
+scalar_field!(Fixed32(u32), as_fixed32, |v| FieldValue::I32(v as i32));
+scalar_field!(Fixed64(u64), as_fixed64, |v| FieldValue::I64(v as i64));
+scalar_field!(Sfixed32(i32), as_i32, FieldValue::I32);
+scalar_field!(Sfixed64(i64), as_sfixed64, FieldValue::I64);
+
+impl<'a> ProtoField<'a> for Cow<'a, str> {
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        _options: &DecodeOptions,
+        _depth: usize,
+    ) -> Result<(), DecodeError> {
+        *self = Cow::Borrowed(value.as_string()?);
+        Ok(())
+    }
+
+    fn is_default(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+        encode_field(field_num, &FieldValue::Len(self.as_bytes()), out);
+    }
+
+    fn to_json_value(&self) -> JsonValue {
+        JsonValue::String(self.to_string())
+    }
+
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError> {
+        let JsonValue::String(text) = value else {
+            return Err(JsonError::InvalidValue { expected: "a string" });
+        };
+        encode_field(field_num, &FieldValue::Len(text.as_bytes()), out);
+        Ok(())
+    }
+
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+        fields.push((name.to_string(), TextValue::String(self.as_bytes().to_vec())));
+    }
+
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError> {
+        let TextValue::String(bytes) = value else {
+            return Err(TextError::invalid("a string"));
+        };
+        if std::str::from_utf8(bytes).is_err() {
+            return Err(TextError::invalid("a UTF-8 string"));
+        }
+        encode_field(field_num, &FieldValue::Len(bytes), out);
+        Ok(())
+    }
+}
+
+impl<'a> ProtoField<'a> for Cow<'a, [u8]> {
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        _options: &DecodeOptions,
+        _depth: usize,
+    ) -> Result<(), DecodeError> {
+        *self = Cow::Borrowed(value.as_bytes()?);
+        Ok(())
+    }
+
+    fn is_default(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+        encode_field(field_num, &FieldValue::Len(self), out);
+    }
+
+    fn to_json_value(&self) -> JsonValue {
+        JsonValue::String(base64_encode(self))
+    }
+
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError> {
+        let JsonValue::String(text) = value else {
+            return Err(JsonError::InvalidValue { expected: "a base64 string" });
+        };
+        encode_field(field_num, &FieldValue::Len(&base64_decode(text)?), out);
+        Ok(())
+    }
+
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+        fields.push((name.to_string(), TextValue::String(self.to_vec())));
+    }
+
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError> {
+        let TextValue::String(bytes) = value else {
+            return Err(TextError::invalid("a string"));
+        };
+        encode_field(field_num, &FieldValue::Len(bytes), out);
+        Ok(())
+    }
+}
+
+// Nested messages are merged field by field, as the protobuf spec requires
+// when a singular message field occurs more than once.
+impl<'a, T> ProtoField<'a> for T
+where
+    T: ProtoMessage<'a> + ProtoEncode + ProtoJson + ProtoText,
+{
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError> {
+        merge_message_at(self, value.as_bytes()?, options, options.nested(depth)?)
+    }
+
+    fn merge_other(&mut self, other: Self) {
+        self.merge_from(other);
+    }
+
+    fn is_default(&self) -> bool {
+        // A message field is present even when all of its own fields are
+        // default, so always write it.
+        false
+    }
+
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+        encode_field(field_num, &FieldValue::Len(&encode(self)), out);
+    }
+
+    fn to_json_value(&self) -> JsonValue {
+        self.to_json()
+    }
+
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError> {
+        let mut message = Vec::new();
+        T::json_to_wire(value, &mut message)?;
+        encode_field(field_num, &FieldValue::Len(&message), out);
+        Ok(())
+    }
+
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+        fields.push((name.to_string(), TextValue::Message(self.to_text())));
+    }
+
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError> {
+        let TextValue::Message(fields) = value else {
+            return Err(TextError::invalid("a message"));
+        };
+        let mut message = Vec::new();
+        T::text_to_wire(fields, &mut message)?;
+        encode_field(field_num, &FieldValue::Len(&message), out);
+        Ok(())
+    }
+}
+
+// Optional fields track presence, so they are written whenever they are set.
+impl<'a, T: ProtoField<'a> + Default> ProtoField<'a> for Option<T> {
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError> {
+        self.get_or_insert_with(T::default).merge_value(value, options, depth)
+    }
+
+    // Presence is what counts, so a value of `Some(0)` is still merged.
+    fn merge_other(&mut self, other: Self) {
+        if let Some(other) = other {
+            match self {
+                Some(value) => value.merge_other(other),
+                None => *self = Some(other),
+            }
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.is_none()
+    }
+
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+        if let Some(value) = self {
+            value.encode_value(field_num, out);
+        }
+    }
+
+    fn to_json_value(&self) -> JsonValue {
+        match self {
+            Some(value) => value.to_json_value(),
+            None => JsonValue::Null,
+        }
+    }
+
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError> {
+        T::encode_json_value(value, field_num, out)
+    }
+
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+        if let Some(value) = self {
+            value.to_text_values(name, fields);
+        }
+    }
+
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError> {
+        T::encode_text_value(value, field_num, out)
+    }
+}
+
+impl<'a, T: ProtoField<'a> + Default> ProtoField<'a> for Vec<T> {
+    fn merge_value(
+        &mut self,
+        value: &FieldValue<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError> {
+        T::merge_repeated(self, value, options, depth)?;
+        options.check_repeated(self.len())
+    }
+
+    fn merge_other(&mut self, other: Self) {
+        self.extend(other);
+    }
+
+    fn is_default(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+        T::encode_repeated(self, field_num, out);
+    }
+
+    fn to_json_value(&self) -> JsonValue {
+        JsonValue::Array(self.iter().map(T::to_json_value).collect())
+    }
+
+    fn encode_json_value(
+        value: &JsonValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JsonError> {
+        let JsonValue::Array(items) = value else {
+            return Err(JsonError::InvalidValue { expected: "an array" });
+        };
+        T::encode_json_repeated(items, field_num, out)
+    }
+
+    fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+        for item in self {
+            item.to_text_values(name, fields);
+        }
+    }
+
+    // Each occurrence of a repeated field in the text format is one element.
+    fn encode_text_value(
+        value: &TextValue,
+        field_num: u64,
+        out: &mut Vec<u8>,
+    ) -> Result<(), TextError> {
+        T::encode_text_value(value, field_num, out)
+    }
+}
+
+/// A map entry, which is encoded as a message with the key as field 1 and the
+/// value as field 2.
+#[derive(Default)]
+struct MapEntry<K, V> {
+    key: K,
+    value: V,
+}
+
+impl<'a, K, V> ProtoMessage<'a> for MapEntry<K, V>
+where
+    K: ProtoField<'a> + Default + 'a,
+    V: ProtoField<'a> + Default + 'a,
+{
+    fn add_field(
+        &mut self,
+        field: Field<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Result<(), DecodeError> {
+        match field.field_num {
+            1 => self.key.merge_value(&field.value, options, depth),
+            2 => self.value.merge_value(&field.value, options, depth),
+            _ => Ok(()),
+        }
+    }
+
+    fn merge_from(&mut self, other: Self) {
+        if !other.key.is_default() {
+            self.key.merge_other(other.key);
+        }
+        if !other.value.is_default() {
+            self.value.merge_other(other.value);
+        }
+    }
+}
+
+/// Decode one entry of a map field into its key and value. A missing key or
+/// value is left at its default.
+fn parse_map_entry<'a, K, V>(
+    value: &FieldValue<'a>,
+    options: &DecodeOptions,
+    depth: usize,
+) -> Result<(K, V), DecodeError>
+where
+    K: ProtoField<'a> + Default + 'a,
+    V: ProtoField<'a> + Default + 'a,
+{
+    let mut entry = MapEntry::default();
+    merge_message_at(&mut entry, value.as_bytes()?, options, options.nested(depth)?)?;
+    Ok((entry.key, entry.value))
+}
+
+/// Append one entry of a map field to `out`.
+fn encode_map_entry<'a, K: ProtoField<'a>, V: ProtoField<'a>>(
+    key: &K,
+    value: &V,
+    field_num: u64,
+    out: &mut Vec<u8>,
+) {
+    let mut entry = Vec::new();
+    key.encode_value(1, &mut entry);
+    value.encode_value(2, &mut entry);
+    encode_field(field_num, &FieldValue::Len(&entry), out);
+}
+
+/// The JSON object key for a map key, which is always a string.
+fn json_map_key(key: JsonValue) -> String {
+    match key {
+        JsonValue::String(text) | JsonValue::Number(text) => text,
+        JsonValue::Bool(value) => value.to_string(),
+        _ => unreachable!("map keys are always scalars"),
+    }
+}
+
+/// Implement `ProtoField` for a map type, given the bounds it needs on keys.
+/// Entries are inserted as they are decoded, so the last entry for a key wins.
+macro_rules! map_field {
+    ($map:ident, K: $($bound:tt)+) => {
+        impl<'a, K, V> ProtoField<'a> for $map<K, V>
+        where
+            K: ProtoField<'a> + Default + 'a + $($bound)+,
+            V: ProtoField<'a> + Default + 'a,
+        {
+            fn merge_value(
+                &mut self,
+                value: &FieldValue<'a>,
+                options: &DecodeOptions,
+                depth: usize,
+            ) -> Result<(), DecodeError> {
+                let (key, value) = parse_map_entry(value, options, depth)?;
+                self.insert(key, value);
+                options.check_repeated(self.len())
+            }
+
+            fn merge_other(&mut self, other: Self) {
+                self.extend(other);
+            }
+
+            fn is_default(&self) -> bool {
+                self.is_empty()
+            }
+
+            fn encode_value(&self, field_num: u64, out: &mut Vec<u8>) {
+                for (key, value) in self {
+                    encode_map_entry(key, value, field_num, out);
+                }
+            }
+
+            fn to_json_value(&self) -> JsonValue {
+                let members = self
+                    .iter()
+                    .map(|(key, value)| (json_map_key(key.to_json_value()), value.to_json_value()))
+                    .collect();
+                JsonValue::Object(members)
+            }
+
+            fn encode_json_value(
+                value: &JsonValue,
+                field_num: u64,
+                out: &mut Vec<u8>,
+            ) -> Result<(), JsonError> {
+                let JsonValue::Object(members) = value else {
+                    return Err(JsonError::InvalidValue { expected: "an object" });
+                };
+                for (key, value) in members {
+                    let mut entry = Vec::new();
+                    let string_key = JsonValue::String(key.clone());
+                    if K::encode_json_value(&string_key, 1, &mut entry).is_err() {
+                        // Keys of bool maps are written as "true" or "false".
+                        let key = match key.as_str() {
+                            "true" => JsonValue::Bool(true),
+                            "false" => JsonValue::Bool(false),
+                            _ => return Err(JsonError::InvalidValue { expected: "a map key" }),
+                        };
+                        K::encode_json_value(&key, 1, &mut entry)?;
+                    }
+                    V::encode_json_value(value, 2, &mut entry)?;
+                    encode_field(field_num, &FieldValue::Len(&entry), out);
+                }
+                Ok(())
+            }
+
+            // The text format writes each entry as a message with `key` and
+            // `value` fields.
+            fn to_text_values(&self, name: &str, fields: &mut Vec<(String, TextValue)>) {
+                for (key, value) in self {
+                    let mut entry = Vec::new();
+                    key.to_text_values("key", &mut entry);
+                    value.to_text_values("value", &mut entry);
+                    fields.push((name.to_string(), TextValue::Message(entry)));
+                }
+            }
+
+            fn encode_text_value(
+                value: &TextValue,
+                field_num: u64,
+                out: &mut Vec<u8>,
+            ) -> Result<(), TextError> {
+                let TextValue::Message(fields) = value else {
+                    return Err(TextError::invalid("a map entry"));
+                };
+                let mut entry = Vec::new();
+                for (name, value) in fields {
+                    match name.as_str() {
+                        "key" => K::encode_text_value(value, 1, &mut entry),
+                        "value" => V::encode_text_value(value, 2, &mut entry),
+                        _ => return Err(TextError::UnknownField(name.clone())),
+                    }
+                    .map_err(|err| err.in_field(name))?;
+                }
+                encode_field(field_num, &FieldValue::Len(&entry), out);
+                Ok(())
+            }
+        }
+
+        impl<K: IntoOwned, V: IntoOwned> IntoOwned for $map<K, V>
+        where
+            K::Owned: $($bound)+,
+        {
+            type Owned = $map<K::Owned, V::Owned>;
+
+            fn into_owned(self) -> Self::Owned {
+                self.into_iter()
+                    .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                    .collect()
+            }
+        }
+    };
+}
+
+map_field!(HashMap, K: Eq + Hash);
+map_field!(BTreeMap, K: Ord);
+
+/// Fields a message did not recognise, kept as their original encoding so that
+/// they are written back out unchanged when the message is re-encoded.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct UnknownFields<'a>(Vec<Cow<'a, [u8]>>);
+
+impl<'a> UnknownFields<'a> {
+    fn add(&mut self, field: &Field<'a>) {
+        self.0.push(Cow::Borrowed(field.raw));
+    }
+
+    /// Append the unknown fields to `out`, in the order they were decoded.
+    fn encode(&self, out: &mut Vec<u8>) {
+        for raw in &self.0 {
+            out.extend_from_slice(raw);
+        }
+    }
+
+    /// Append the unknown fields of another message, as `merge_from` does.
+    fn append(&mut self, other: UnknownFields<'a>) {
+        self.0.extend(other.0);
+    }
+}
+
+impl IntoOwned for UnknownFields<'_> {
+    type Owned = UnknownFields<'static>;
+
+    fn into_owned(self) -> Self::Owned {
+        UnknownFields(self.0.into_owned())
+    }
+}
+
+/// Declare a message struct whose fields are tagged with their field numbers,
+/// generating its `ProtoMessage`, `ProtoEncode`, `ProtoJson` and `ProtoText`
+/// implementations.
+///
+/// The struct may borrow from the input through a single lifetime parameter,
+/// in which case the tagged fields can be followed by `#[proto(oneof)]`
+/// fields holding an `Option` of a `proto_oneof!` enum, and then by a
+/// `#[proto(unknown)]` field of type `UnknownFields` to keep fields it
+/// doesn't recognise. Each tagged field's type must implement `ProtoField`.
+/// Giving two fields the same tag is reported as an unreachable pattern. As
+/// in proto3, fields holding their default value are not encoded.
+macro_rules! proto_message {
+    (
+        @impl [$lt:lifetime] $name:ty
+        { $($tag:literal => $field:ident: $ty:ty),* }
+        { $($oneof:ident: $oneof_ty:ty),* }
+        [$($unknown:ident)?]
+    ) => {
+        impl<$lt> ProtoMessage<$lt> for $name {
+            fn add_field(
+                &mut self,
+                field: Field<$lt>,
+                options: &DecodeOptions,
+                depth: usize,
+            ) -> Result<(), DecodeError> {
+                match field.field_num {
+                    $($tag => {
+                        ProtoField::merge_value(&mut self.$field, &field.value, options, depth)
+                    })*
+                    _ => {
+                        $(
+                            let member =
+                                ProtoOneof::merge_field(&mut self.$oneof, &field, options, depth);
+                            if let Some(result) = member {
+                                return result;
+                            }
+                        )*
+                        $(self.$unknown.add(&field);)?
+                        Ok(())
+                    }
+                }
+            }
+
+            fn merge_from(&mut self, other: Self) {
+                $(
+                    if !ProtoField::is_default(&other.$field) {
+                        ProtoField::merge_other(&mut self.$field, other.$field);
+                    }
+                )*
+                $(ProtoOneof::merge_member(&mut self.$oneof, other.$oneof);)*
+                $(self.$unknown.append(other.$unknown);)?
+            }
+        }
+
+        impl<$lt> ProtoEncode for $name {
+            fn encode_fields(&self, out: &mut Vec<u8>) {
+                $(
+                    if !ProtoField::is_default(&self.$field) {
+                        ProtoField::encode_value(&self.$field, $tag, out);
+                    }
+                )*
+                $(ProtoOneof::encode_member(&self.$oneof, out);)*
+                $(self.$unknown.encode(out);)?
+            }
+        }
+
+        // Unknown fields have no JSON form, so they are left out.
+        impl<$lt> ProtoJson for $name {
+            fn to_json(&self) -> JsonValue {
+                let mut members = Vec::new();
+                $(
+                    if !ProtoField::is_default(&self.$field) {
+                        let value = ProtoField::to_json_value(&self.$field);
+                        members.push((json_name(stringify!($field)), value));
+                    }
+                )*
+                $(members.extend(ProtoOneof::to_json_member(&self.$oneof));)*
+                JsonValue::Object(members)
+            }
+
+            fn json_to_wire(value: &JsonValue, out: &mut Vec<u8>) -> Result<(), JsonError> {
+                let JsonValue::Object(members) = value else {
+                    return Err(JsonError::InvalidValue { expected: "an object" });
+                };
+                for (name, value) in members {
+                    // Both the JSON name and the original field name are
+                    // accepted, and null means the field is not set.
+                    let matches = |field: &str| {
+                        *name == json_name(field) || name == field.trim_end_matches('_')
+                    };
+                    $(
+                        if matches(stringify!($field)) {
+                            if *value != JsonValue::Null {
+                                <$ty as ProtoField>::encode_json_value(value, $tag, out)?;
+                            }
+                            continue;
+                        }
+                    )*
+                    $(
+                        let member =
+                            <$oneof_ty as ProtoOneof>::encode_json_member(name, value, out);
+                        if let Some(result) = member {
+                            result?;
+                            continue;
+                        }
+                    )*
+                    return Err(JsonError::UnknownField(name.clone()));
+                }
+                Ok(())
+            }
+        }
+
+        impl<$lt> ProtoText for $name {
+            fn to_text(&self) -> Vec<(String, TextValue)> {
+                let mut fields = Vec::new();
+                $(
+                    if !ProtoField::is_default(&self.$field) {
+                        let name = stringify!($field).trim_end_matches('_');
+                        ProtoField::to_text_values(&self.$field, name, &mut fields);
+                    }
+                )*
+                $(ProtoOneof::to_text_member(&self.$oneof, &mut fields);)*
+                fields
+            }
+
+            fn text_to_wire(
+                fields: &[(String, TextValue)],
+                out: &mut Vec<u8>,
+            ) -> Result<(), TextError> {
+                for (name, value) in fields {
+                    $(
+                        if name == stringify!($field).trim_end_matches('_') {
+                            <$ty as ProtoField>::encode_text_value(value, $tag, out)
+                                .map_err(|err| err.in_field(name))?;
+                            continue;
+                        }
+                    )*
+                    $(
+                        let member =
+                            <$oneof_ty as ProtoOneof>::encode_text_member(name, value, out);
+                        if let Some(result) = member {
+                            result.map_err(|err| err.in_field(name))?;
+                            continue;
+                        }
+                    )*
+                    return Err(TextError::UnknownField(name.clone()));
+                }
+                Ok(())
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        struct $name:ident<$lt:lifetime> {
+            $(
+                #[proto(tag = $tag:literal)]
+                $field:ident: $ty:ty
+            ),*
+            $(
+                , #[proto(oneof)]
+                $oneof:ident: $oneof_ty:ty
+            )*
+            $(
+                , #[proto(unknown)]
+                $unknown:ident: $unknown_ty:ty
+            )?
+            $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name<$lt> {
+            $($field: $ty,)*
+            $($oneof: $oneof_ty,)*
+            $($unknown: $unknown_ty,)?
+        }
+
+        proto_message!(
+            @impl [$lt] $name<$lt>
+            { $($tag => $field: $ty),* }
+            { $($oneof: $oneof_ty),* }
+            [$($unknown)?]
+        );
+
+        impl<$lt> IntoOwned for $name<$lt> {
+            type Owned = $name<'static>;
+
+            fn into_owned(self) -> Self::Owned {
+                $name {
+                    $($field: IntoOwned::into_owned(self.$field),)*
+                    $($oneof: IntoOwned::into_owned(self.$oneof),)*
+                    $($unknown: IntoOwned::into_owned(self.$unknown),)?
+                }
+            }
+        }
+    };
+    // A message with no fields of its own, which only keeps unknown fields.
+    // The `@impl` rule would leave most of its bindings unused, so the traits
+    // are implemented directly.
+    (
+        $(#[$meta:meta])*
+        struct $name:ident<$lt:lifetime> {
+            #[proto(unknown)]
+            $unknown:ident: $unknown_ty:ty
+            $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name<$lt> {
+            $unknown: $unknown_ty,
+        }
+
+        impl<$lt> ProtoMessage<$lt> for $name<$lt> {
+            fn add_field(
+                &mut self,
+                field: Field<$lt>,
+                _options: &DecodeOptions,
+                _depth: usize,
+            ) -> Result<(), DecodeError> {
+                self.$unknown.add(&field);
+                Ok(())
+            }
+
+            fn merge_from(&mut self, other: Self) {
+                self.$unknown.append(other.$unknown);
+            }
+        }
+
+        impl ProtoEncode for $name<'_> {
+            fn encode_fields(&self, out: &mut Vec<u8>) {
+                self.$unknown.encode(out);
+            }
+        }
+
+        impl ProtoJson for $name<'_> {
+            fn to_json(&self) -> JsonValue {
+                JsonValue::Object(Vec::new())
+            }
+
+            fn json_to_wire(value: &JsonValue, _out: &mut Vec<u8>) -> Result<(), JsonError> {
+                match value {
+                    JsonValue::Object(members) => match members.first() {
+                        Some((name, _)) => Err(JsonError::UnknownField(name.clone())),
+                        None => Ok(()),
+                    },
+                    _ => Err(JsonError::InvalidValue { expected: "an object" }),
+                }
+            }
+        }
+
+        impl ProtoText for $name<'_> {
+            fn to_text(&self) -> Vec<(String, TextValue)> {
+                Vec::new()
+            }
+
+            fn text_to_wire(
+                fields: &[(String, TextValue)],
+                _out: &mut Vec<u8>,
+            ) -> Result<(), TextError> {
+                match fields.first() {
+                    Some((name, _)) => Err(TextError::UnknownField(name.clone())),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        impl<$lt> IntoOwned for $name<$lt> {
+            type Owned = $name<'static>;
+
+            fn into_owned(self) -> Self::Owned {
+                $name { $unknown: IntoOwned::into_owned(self.$unknown) }
+            }
+        }
+    };
+    // A message made up only of `oneof` groups.
+    (
+        $(#[$meta:meta])*
+        struct $name:ident<$lt:lifetime> {
+            $(
+                #[proto(oneof)]
+                $oneof:ident: $oneof_ty:ty
+            ),+
+            $(
+                , #[proto(unknown)]
+                $unknown:ident: $unknown_ty:ty
+            )?
+            $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name<$lt> {
+            $($oneof: $oneof_ty,)*
+            $($unknown: $unknown_ty,)?
+        }
+
+        proto_message!(@impl [$lt] $name<$lt> {} { $($oneof: $oneof_ty),* } [$($unknown)?]);
+
+        impl<$lt> IntoOwned for $name<$lt> {
+            type Owned = $name<'static>;
+
+            fn into_owned(self) -> Self::Owned {
+                $name {
+                    $($oneof: IntoOwned::into_owned(self.$oneof),)*
+                    $($unknown: IntoOwned::into_owned(self.$unknown),)?
+                }
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        struct $name:ident {
+            $(
+                #[proto(tag = $tag:literal)]
+                $field:ident: $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name {
+            $($field: $ty),*
+        }
+
+        proto_message!(@impl ['a] $name { $($tag => $field: $ty),* } {} []);
+
+        impl IntoOwned for $name {
+            type Owned = $name;
+
+            fn into_owned(self) -> Self::Owned {
+                $name {
+                    $($field: IntoOwned::into_owned(self.$field),)*
+                }
+            }
+        }
+    };
+}
+
+/// A `oneof` group, stored in a message as an `Option` of an enum with a
+/// variant for each member field. Implemented by `proto_oneof!`.
+trait ProtoOneof<'a> {
+    /// Merge `field` into the group if it is one of its members, returning
+    /// `None` otherwise. Only one member can be set, so a member other than the
+    /// current one replaces it.
+    fn merge_field(
+        &mut self,
+        field: &Field<'a>,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> Option<Result<(), DecodeError>>;
+
+    /// Merge the member set in `other`, if any, into the group. The same
+    /// member is merged into the current one, while a different member
+    /// replaces it.
+    fn merge_member(&mut self, other: Self)
+    where
+        Self: Sized;
+
+    /// Append the member that is set to `out`, even if it holds its default
+    /// value.
+    fn encode_member(&self, out: &mut Vec<u8>);
+
+    /// The JSON name and value of the member that is set.
+    fn to_json_member(&self) -> Option<(String, JsonValue)>;
+
+    /// Append the member called `name`, given in JSON, to `out` as wire data,
+    /// returning `None` if the group has no such member.
+    fn encode_json_member(
+        name: &str,
+        value: &JsonValue,
+        out: &mut Vec<u8>,
+    ) -> Option<Result<(), JsonError>>
+    where
+        Self: Sized;
+
+    /// Append the text format value of the member that is set to `fields`.
+    fn to_text_member(&self, fields: &mut Vec<(String, TextValue)>);
+
+    /// Append the member called `name`, given in text format, to `out` as wire
+    /// data, returning `None` if the group has no such member.
+    fn encode_text_member(
+        name: &str,
+        value: &TextValue,
+        out: &mut Vec<u8>,
+    ) -> Option<Result<(), TextError>>
+    where
+        Self: Sized;
+}
+
+/// Declare an enum for a `oneof` group, with one variant per member field
+/// tagged with its field number. A message holds the group as an `Option` of
+/// the enum, marked `#[proto(oneof)]` in `proto_message!`. In JSON and the
+/// text format, members are named after their variants in snake_case.
+macro_rules! proto_oneof {
+    (
+        @impl [$lt:lifetime] $name:ty, $enum:ident
+        { $($tag:literal => $variant:ident($ty:ty)),* }
+    ) => {
+        impl<$lt> ProtoOneof<$lt> for Option<$name> {
+            fn merge_field(
+                &mut self,
+                field: &Field<$lt>,
+                options: &DecodeOptions,
+                depth: usize,
+            ) -> Option<Result<(), DecodeError>> {
+                match field.field_num {
+                    $(
+                        $tag => {
+                            if !matches!(self, Some($enum::$variant(_))) {
+                                *self = Some($enum::$variant(Default::default()));
+                            }
+                            let Some($enum::$variant(value)) = self else {
+                                unreachable!("the member was just set");
+                            };
+                            Some(ProtoField::merge_value(value, &field.value, options, depth))
+                        }
+                    )*
+                    _ => None,
+                }
+            }
+
+            fn merge_member(&mut self, other: Self) {
+                match (&mut *self, other) {
+                    $(
+                        (Some($enum::$variant(value)), Some($enum::$variant(other))) => {
+                            ProtoField::merge_other(value, other);
+                        }
+                    )*
+                    (_, None) => (),
+                    (_, other) => *self = other,
+                }
+            }
+
+            fn encode_member(&self, out: &mut Vec<u8>) {
+                match self {
+                    $(Some($enum::$variant(value)) => ProtoField::encode_value(value, $tag, out),)*
+                    None => (),
+                }
+            }
+
+            fn to_json_member(&self) -> Option<(String, JsonValue)> {
+                match self {
+                    $(
+                        Some($enum::$variant(value)) => Some((
+                            json_name(&snake_case(stringify!($variant))),
+                            ProtoField::to_json_value(value),
+                        )),
+                    )*
+                    None => None,
+                }
+            }
+
+            fn encode_json_member(
+                name: &str,
+                value: &JsonValue,
+                out: &mut Vec<u8>,
+            ) -> Option<Result<(), JsonError>> {
+                $(
+                    let field = snake_case(stringify!($variant));
+                    if name == json_name(&field) || name == field {
+                        if *value == JsonValue::Null {
+                            return Some(Ok(()));
+                        }
+                        return Some(<$ty as ProtoField>::encode_json_value(value, $tag, out));
+                    }
+                )*
+                None
+            }
+
+            fn to_text_member(&self, fields: &mut Vec<(String, TextValue)>) {
+                match self {
+                    $(
+                        Some($enum::$variant(value)) => {
+                            let name = snake_case(stringify!($variant));
+                            ProtoField::to_text_values(value, &name, fields);
+                        }
+                    )*
+                    None => (),
+                }
+            }
+
+            fn encode_text_member(
+                name: &str,
+                value: &TextValue,
+                out: &mut Vec<u8>,
+            ) -> Option<Result<(), TextError>> {
+                $(
+                    if name == snake_case(stringify!($variant)) {
+                        return Some(<$ty as ProtoField>::encode_text_value(value, $tag, out));
+                    }
+                )*
+                None
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        enum $name:ident<$lt:lifetime> {
+            $(
+                #[proto(tag = $tag:literal)]
+                $variant:ident($ty:ty)
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        enum $name<$lt> {
+            $($variant($ty),)*
+        }
+
+        proto_oneof!(@impl [$lt] $name<$lt>, $name { $($tag => $variant($ty)),* });
+
+        impl<$lt> IntoOwned for $name<$lt> {
+            type Owned = $name<'static>;
+
+            fn into_owned(self) -> Self::Owned {
+                match self {
+                    $($name::$variant(value) => $name::$variant(IntoOwned::into_owned(value)),)*
+                }
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        enum $name:ident {
+            $(
+                #[proto(tag = $tag:literal)]
+                $variant:ident($ty:ty)
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        enum $name {
+            $($variant($ty),)*
+        }
+
+        proto_oneof!(@impl ['a] $name, $name { $($tag => $variant($ty)),* });
+
+        impl IntoOwned for $name {
+            type Owned = $name;
+
+            fn into_owned(self) -> Self::Owned {
+                match self {
+                    $($name::$variant(value) => $name::$variant(IntoOwned::into_owned(value)),)*
+                }
+            }
+        }
+    };
+}
+
+/// Declare an open enum, whose fields are encoded as `int32` values. Numbers
+/// the enum doesn't list are kept as `Unrecognized`, so they survive
+/// re-encoding. The enum must derive `Clone`, `Copy` and `PartialEq`; its
+/// default is the variant numbered 0. In JSON and the text format, values are
+/// named after their variants in SCREAMING_SNAKE_CASE.
+macro_rules! proto_enum {
+    (
+        $(#[$meta:meta])*
+        enum $name:ident {
+            $($variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        enum $name {
+            $($variant,)*
+            Unrecognized(i32),
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::from(0)
+            }
+        }
+
+        impl From<i32> for $name {
+            fn from(value: i32) -> Self {
+                match value {
+                    $($value => $name::$variant,)*
+                    value => $name::Unrecognized(value),
+                }
+            }
+        }
+
+        impl From<$name> for i32 {
+            fn from(value: $name) -> i32 {
+                match value {
+                    $($name::$variant => $value,)*
+                    $name::Unrecognized(value) => value,
+                }
+            }
+        }
+
+        // Unrecognized values have no name, so they are written as numbers.
+        impl JsonScalar for $name {
+            fn to_json(self) -> JsonValue {
+                match self {
+                    $(
+                        $name::$variant => {
+                            JsonValue::String(snake_case(stringify!($variant)).to_uppercase())
+                        }
+                    )*
+                    $name::Unrecognized(value) => JsonValue::Number(value.to_string()),
+                }
+            }
+
+            fn from_json(value: &JsonValue) -> Result<Self, JsonError> {
+                if let JsonValue::String(name) = value {
+                    $(
+                        if *name == snake_case(stringify!($variant)).to_uppercase() {
+                            return Ok($name::$variant);
+                        }
+                    )*
+                }
+                i32::from_json(value).map(Self::from).map_err(|_| JsonError::InvalidValue {
+                    expected: concat!("a ", stringify!($name), " value"),
+                })
+            }
+        }
+
+        scalar_field!(
+            @impl $name,
+            $name,
+            std::convert::identity,
+            std::convert::identity,
+            as_enum,
+            |v| FieldValue::Varint(i32::from(v) as i64 as u64)
+        );
+    };
+}
+
+proto_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum PhoneType {
+        Mobile = 0,
+        Home = 1,
+        Work = 2,
+    }
+}
+
+proto_message! {
+    #[derive(Debug, Default, PartialEq)]
+    struct PhoneNumber<'a> {
+        #[proto(tag = 1)]
+        number: Cow<'a, str>,
+        #[proto(tag = 2)]
+        type_: PhoneType,
+        #[proto(unknown)]
+        unknown: UnknownFields<'a>,
+    }
+}
+
+proto_message! {
+    #[derive(Debug, Default, PartialEq)]
+    struct Person<'a> {
+        #[proto(tag = 1)]
+        name: Cow<'a, str>,
+        #[proto(tag = 2)]
+        id: u64,
+        #[proto(tag = 3)]
+        phone: Vec<PhoneNumber<'a>>,
+        #[proto(tag = 5)]
+        labels: BTreeMap<Cow<'a, str>, Cow<'a, str>>,
+        #[proto(unknown)]
+        unknown: UnknownFields<'a>,
+    }
+}
+
+proto_oneof! {
+    #[derive(Debug, PartialEq)]
+    enum Source<'a> {
+        #[proto(tag = 7)]
+        Device(Cow<'a, str>),
+        #[proto(tag = 8)]
+        Manual(bool),
+    }
+}
+
+// A sensor reading, using the integer types that pick their own encoding.
+proto_message! {
+    #[derive(Debug, Default, PartialEq)]
+    struct Reading<'a> {
+        #[proto(tag = 1)]
+        sensor: Fixed32,
+        #[proto(tag = 2)]
+        timestamp: Fixed64,
+        #[proto(tag = 3)]
+        celsius: Sint32,
+        #[proto(tag = 4)]
+        drift: Sint64,
+        #[proto(tag = 5)]
+        low: Sfixed32,
+        #[proto(tag = 6)]
+        high: Sfixed64,
+        #[proto(oneof)]
+        source: Option<Source<'a>>,
+    }
+}
+
+/// A JSON value. Numbers keep their original text so that 64-bit integers
+/// don't lose precision.
+#[derive(Debug, Clone, PartialEq)]
+enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    /// Members in the order they were written.
+    Object(Vec<(String, JsonValue)>),
+}
+
+/// An error converting between JSON and protobuf messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum JsonError {
+    /// The text is not valid JSON.
+    Syntax { offset: usize, message: &'static str },
+    /// An object has a member that is not a field of the message.
+    UnknownField(String),
+    /// A value has the wrong JSON type, or does not fit the field's type.
+    InvalidValue { expected: &'static str },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax { offset, message } => write!(f, "{message} at byte {offset}"),
+            JsonError::UnknownField(name) => write!(f, "unknown field `{name}`"),
+            JsonError::InvalidValue { expected } => write!(f, "expected {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl JsonValue {
+    /// Parse a complete JSON document.
+    fn parse(text: &str) -> Result<JsonValue, JsonError> {
+        let mut parser = JsonParser { text: text.as_bytes(), pos: 0, depth: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// Append the value to `out` as compact JSON.
+    fn write(&self, out: &mut String) {
+        match self {
+            JsonValue::Null => out.push_str("null"),
+            JsonValue::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            JsonValue::Number(text) => out.push_str(text),
+            JsonValue::String(text) => write_json_string(text, out),
+            JsonValue::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write(out);
+                }
+                out.push(']');
+            }
+            JsonValue::Object(members) => {
+                out.push('{');
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_json_string(name, out);
+                    out.push(':');
+                    value.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_json_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How deeply JSON arrays and objects may nest, matching the default depth
+/// limit for binary messages in `DecodeOptions`.
+const MAX_JSON_DEPTH: usize = 100;
+
+struct JsonParser<'t> {
+    text: &'t [u8],
+    pos: usize,
+    /// How many arrays and objects enclose the current position.
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &'static str) -> JsonError {
+        JsonError::Syntax { offset: self.pos, message }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(|b| b" \t\r\n".contains(b)) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.text.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), JsonError> {
+        if !self.eat(byte) {
+            return Err(self.error(message));
+        }
+        Ok(())
+    }
+
+    /// Step past the `[` or `{` opening an array or object.
+    fn enter(&mut self) -> Result<(), JsonError> {
+        if self.depth == MAX_JSON_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, JsonError> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        for (word, value) in [
+            (&b"null"[..], JsonValue::Null),
+            (b"true", JsonValue::Bool(true)),
+            (b"false", JsonValue::Bool(false)),
+        ] {
+            if rest.starts_with(word) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        match rest.first() {
+            Some(b'"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some(b'[') => {
+                self.enter()?;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.parse_value()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',', "expected `,` or `]`")?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(JsonValue::Array(items))
+            }
+            Some(b'{') => {
+                self.enter()?;
+                let mut members = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        if self.text.get(self.pos) != Some(&b'"') {
+                            return Err(self.error("expected a member name"));
+                        }
+                        let name = self.parse_string()?;
+                        self.expect(b':', "expected `:`")?;
+                        members.push((name, self.parse_value()?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        self.expect(b',', "expected `,` or `}`")?;
+                    }
+                }
+                self.depth -= 1;
+                Ok(JsonValue::Object(members))
+            }
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let start = parser.pos;
+            while parser.text.get(parser.pos).is_some_and(u8::is_ascii_digit) {
+                parser.pos += 1;
+            }
+            parser.pos > start
+        };
+        if self.text[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        if !digits(self) {
+            return Err(self.error("expected digits"));
+        }
+        if self.text.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("expected digits"));
+            }
+        }
+        if matches!(self.text.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.text.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("expected digits"));
+            }
+        }
+        let text = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        Ok(JsonValue::Number(text.to_string()))
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        // Skip the opening quote.
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&b) = self.text.get(self.pos) else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.pos) else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            // Characters outside the BMP are written as a
+                            // UTF-16 surrogate pair.
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("invalid \\u escape"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or(self.error("invalid \\u escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                0x00..=0x1f => return Err(self.error("control character in string")),
+                _ => bytes.push(b),
+            }
+        }
+        // The input was a `&str` and escapes produce whole characters, so
+        // this is always valid UTF-8.
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        // Check each byte, since `from_str_radix` would also accept a sign.
+        let digits = self.text.get(self.pos..self.pos + 4).unwrap_or_default();
+        if digits.len() != 4 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(self.error("invalid \\u escape"));
+        }
+        let digits = std::str::from_utf8(digits).unwrap();
+        let code = u32::from_str_radix(digits, 16).unwrap();
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes as padded standard base64, as proto3 JSON does for `bytes`.
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode standard or URL-safe base64, with or without padding.
+fn base64_decode(text: &str) -> Result<Vec<u8>, JsonError> {
+    let invalid = JsonError::InvalidValue { expected: "base64" };
+    let mut out = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(invalid),
+        };
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    // Leftover bits can only come from padding, and 6 leftover bits mean a
+    // dangling character.
+    if bits >= 6 {
+        return Err(invalid);
+    }
+    Ok(out)
+}
+
+/// A Rust scalar that has a proto3 JSON representation.
+trait JsonScalar: Sized {
+    fn to_json(self) -> JsonValue;
+    fn from_json(value: &JsonValue) -> Result<Self, JsonError>;
+}
+
+/// Read an integer written either as a JSON number or as a string.
+fn json_integer(value: &JsonValue) -> Option<i128> {
+    let (JsonValue::Number(text) | JsonValue::String(text)) = value else {
+        return None;
+    };
+    if let Ok(n) = text.parse() {
+        return Some(n);
+    }
+    // Numbers such as `1e3` or `5.0` are fine as long as they are integral.
+    let n: f64 = text.parse().ok()?;
+    (n.fract() == 0.0 && n.abs() < 2f64.powi(64)).then_some(n as i128)
+}
+
+/// Implement `JsonScalar` for integers, which are written as JSON numbers if
+/// they are 32-bit and as strings if they are 64-bit, since JavaScript numbers
+/// cannot hold every 64-bit value.
+macro_rules! json_integer {
+    ($($ty:ty => $quoted:literal),*) => {
+        $(
+            impl JsonScalar for $ty {
+                fn to_json(self) -> JsonValue {
+                    if $quoted {
+                        JsonValue::String(self.to_string())
+                    } else {
+                        JsonValue::Number(self.to_string())
+                    }
+                }
+
+                fn from_json(value: &JsonValue) -> Result<Self, JsonError> {
+                    json_integer(value)
+                        .and_then(|n| <$ty>::try_from(n).ok())
+                        .ok_or(JsonError::InvalidValue { expected: stringify!($ty) })
+                }
+            }
+        )*
+    };
+}
+
+json_integer!(u32 => false, i32 => false, u64 => true, i64 => true);
+
+impl JsonScalar for bool {
+    fn to_json(self) -> JsonValue {
+        JsonValue::Bool(self)
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, JsonError> {
+        match value {
+            JsonValue::Bool(value) => Ok(*value),
+            _ => Err(JsonError::InvalidValue { expected: "a boolean" }),
+        }
+    }
+}
+
+impl JsonScalar for f64 {
+    fn to_json(self) -> JsonValue {
+        // JSON has no NaN or infinity, so these are written as strings.
+        if self.is_nan() {
+            JsonValue::String("NaN".into())
+        } else if self.is_infinite() {
+            JsonValue::String(if self > 0.0 { "Infinity" } else { "-Infinity" }.into())
+        } else {
+            JsonValue::Number(format!("{self:?}"))
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, JsonError> {
+        match value {
+            JsonValue::Number(text) | JsonValue::String(text) => match text.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "Infinity" => Ok(f64::INFINITY),
+                "-Infinity" => Ok(f64::NEG_INFINITY),
+                _ => text.parse().map_err(|_| JsonError::InvalidValue { expected: "a number" }),
+            },
+            _ => Err(JsonError::InvalidValue { expected: "a number" }),
+        }
+    }
+}
+
+impl JsonScalar for f32 {
+    fn to_json(self) -> JsonValue {
+        if self.is_finite() {
+            JsonValue::Number(format!("{self:?}"))
+        } else {
+            (self as f64).to_json()
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Self, JsonError> {
+        let value = f64::from_json(value)?;
+        if value.is_finite() && value.abs() > f32::MAX as f64 {
+            return Err(JsonError::InvalidValue { expected: "a float" });
+        }
+        Ok(value as f32)
+    }
+}
+
+/// The proto3 JSON mapping for a message type.
+trait ProtoJson {
+    /// Convert the message to a JSON object, leaving out default values.
+    fn to_json(&self) -> JsonValue;
+
+    /// Encode a JSON object describing a message of this type as wire data.
+    ///
+    /// Going through the wire format lets `parse_message` build the message,
+    /// borrowing its strings from the encoded bytes.
+    fn json_to_wire(value: &JsonValue, out: &mut Vec<u8>) -> Result<(), JsonError>;
+}
+
+/// The JSON name of a field: the lowerCamelCase form of its protobuf name.
+/// A trailing underscore, used to avoid Rust keywords, is dropped.
+fn json_name(field: &str) -> String {
+    let mut name = String::new();
+    let mut upper = false;
+    for c in field.trim_end_matches('_').chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            name.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// The snake_case form of a CamelCase Rust name, such as a `proto_enum!` or
+/// `proto_oneof!` variant.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// Print a message as canonical proto3 JSON.
+fn to_json<T: ProtoJson>(message: &T) -> String {
+    let mut out = String::new();
+    message.to_json().write(&mut out);
+    out
+}
+
+/// Convert proto3 JSON for a message of type `T` into wire data, which
+/// `parse_message::<T>` can then decode.
+fn json_to_wire<T: ProtoJson>(json: &str) -> Result<Vec<u8>, JsonError> {
+    let mut out = Vec::new();
+    T::json_to_wire(&JsonValue::parse(json)?, &mut out)?;
+    Ok(out)
+}
+
+/// A value in the protobuf text format.
+#[derive(Debug, Clone, PartialEq)]
+enum TextValue {
+    /// A number or identifier, such as `42`, `-1.5e3` or `true`.
+    Scalar(String),
+    /// The bytes of one or more adjacent string literals.
+    String(Vec<u8>),
+    /// A nested message, as its fields in order.
+    Message(Vec<(String, TextValue)>),
+}
+
+/// An error converting between the text format and protobuf messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TextError {
+    /// The text could not be parsed.
+    Syntax { line: usize, message: &'static str },
+    /// A message has a field name that it does not define.
+    UnknownField(String),
+    /// A field's value does not fit the field's type.
+    InvalidValue { field: String, expected: &'static str },
+}
+
+impl TextError {
+    fn invalid(expected: &'static str) -> Self {
+        TextError::InvalidValue { field: String::new(), expected }
+    }
+
+    /// Name the field an `InvalidValue` error belongs to, unless a nested
+    /// field was already named.
+    fn in_field(self, name: &str) -> Self {
+        match self {
+            TextError::InvalidValue { field, expected } if field.is_empty() => {
+                TextError::InvalidValue { field: name.to_string(), expected }
+            }
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextError::Syntax { line, message } => write!(f, "line {line}: {message}"),
+            TextError::UnknownField(name) => write!(f, "unknown field `{name}`"),
+            TextError::InvalidValue { field, expected } => {
+                write!(f, "expected {expected} for field `{field}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextError {}
+
+/// The text format mapping for a message type.
+trait ProtoText {
+    /// The message's fields and their values, leaving out default values.
+    fn to_text(&self) -> Vec<(String, TextValue)>;
+
+    /// Encode text format fields of a message of this type as wire data.
+    fn text_to_wire(fields: &[(String, TextValue)], out: &mut Vec<u8>) -> Result<(), TextError>;
+}
+
+/// The text format spelling of a scalar, given its JSON form.
+fn text_scalar(value: JsonValue) -> String {
+    match value {
+        JsonValue::Bool(value) => value.to_string(),
+        JsonValue::Number(text) => text,
+        // 64-bit integers and non-finite floats are only quoted in JSON.
+        JsonValue::String(text) => match text.as_str() {
+            "NaN" => "nan".to_string(),
+            "Infinity" => "inf".to_string(),
+            "-Infinity" => "-inf".to_string(),
+            _ => text,
+        },
+        _ => unreachable!("scalars are never arrays, objects or null"),
+    }
+}
+
+/// The JSON form of a text format scalar, so that `JsonScalar` can read it.
+fn text_scalar_to_json(token: &str) -> JsonValue {
+    let lower = token.to_ascii_lowercase();
+    match lower.as_str() {
+        "true" | "t" => return JsonValue::Bool(true),
+        "false" | "f" => return JsonValue::Bool(false),
+        "inf" | "infinity" => return JsonValue::String("Infinity".into()),
+        "-inf" | "-infinity" => return JsonValue::String("-Infinity".into()),
+        "nan" => return JsonValue::String("NaN".into()),
+        _ => (),
+    }
+    // Other identifiers name enum values.
+    if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return JsonValue::String(token.to_string());
+    }
+    let (sign, digits) = match lower.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", lower.as_str()),
+    };
+    if let Some(hex) = digits.strip_prefix("0x") {
+        if let Ok(value) = i128::from_str_radix(hex, 16) {
+            return JsonValue::Number(format!("{sign}{value}"));
+        }
+    }
+    // Floats may carry an `f` suffix, as in `1.5f`.
+    JsonValue::Number(lower.strip_suffix('f').unwrap_or(&lower).to_string())
+}
+
+/// Append text format fields to `out`, one per line.
+fn write_text_fields(fields: &[(String, TextValue)], depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    for (name, value) in fields {
+        match value {
+            TextValue::Scalar(token) => out.push_str(&format!("{indent}{name}: {token}\n")),
+            TextValue::String(bytes) => {
+                out.push_str(&format!("{indent}{name}: \""));
+                for chunk in bytes.utf8_chunks() {
+                    for c in chunk.valid().chars() {
+                        match c {
+                            '"' => out.push_str("\\\""),
+                            '\\' => out.push_str("\\\\"),
+                            '\n' => out.push_str("\\n"),
+                            '\r' => out.push_str("\\r"),
+                            '\t' => out.push_str("\\t"),
+                            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+                            c => out.push(c),
+                        }
+                    }
+                    for b in chunk.invalid() {
+                        out.push_str(&format!("\\x{b:02x}"));
+                    }
+                }
+                out.push_str("\"\n");
+            }
+            TextValue::Message(fields) => {
+                out.push_str(&format!("{indent}{name} {{\n"));
+                write_text_fields(fields, depth + 1, out);
+                out.push_str(&format!("{indent}}}\n"));
+            }
+        }
+    }
+}
+
+/// How deeply text format messages may nest, like `MAX_JSON_DEPTH`.
+const MAX_TEXT_DEPTH: usize = 100;
+
+struct TextParser<'t> {
+    text: &'t [u8],
+    pos: usize,
+    line: usize,
+    /// How many messages enclose the current position.
+    depth: usize,
+}
+
+impl TextParser<'_> {
+    fn error(&self, message: &'static str) -> TextError {
+        TextError::Syntax { line: self.line, message }
+    }
+
+    /// Skip whitespace and `#` comments, returning the next byte.
+    fn peek(&mut self) -> Option<u8> {
+        loop {
+            match self.text.get(self.pos) {
+                Some(b'\n') => {
+                    self.line += 1;
+                    self.pos += 1;
+                }
+                Some(b' ' | b'\t' | b'\r') => self.pos += 1,
+                Some(b'#') => {
+                    while self.text.get(self.pos).is_some_and(|&b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                other => return other.copied(),
+            }
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Read an identifier or number, such as `name`, `-12`, `1.5e-3` or `-inf`.
+    fn token(&mut self) -> Result<String, TextError> {
+        self.peek();
+        let start = self.pos;
+        if self.text.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while let Some(&b) = self.text.get(self.pos) {
+            let exponent_sign = matches!(b, b'+' | b'-')
+                && self.pos > start
+                && matches!(self.text[self.pos - 1], b'e' | b'E')
+                && self.text[start..self.pos].iter().any(u8::is_ascii_digit);
+            if !(b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || exponent_sign) {
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.error("expected a name or value"));
+        }
+        Ok(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned())
+    }
+
+    /// Parse one or more adjacent string literals into their bytes.
+    fn strings(&mut self) -> Result<Vec<u8>, TextError> {
+        let mut bytes = Vec::new();
+        while let Some(quote @ (b'"' | b'\'')) = self.peek() {
+            self.pos += 1;
+            loop {
+                let Some(&b) = self.text.get(self.pos) else {
+                    return Err(self.error("unterminated string"));
+                };
+                self.pos += 1;
+                if b == quote {
+                    break;
+                }
+                match b {
+                    b'\n' => return Err(self.error("unterminated string")),
+                    b'\\' => bytes.push(self.escape()?),
+                    _ => bytes.push(b),
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Parse the rest of an escape sequence in a string literal.
+    fn escape(&mut self) -> Result<u8, TextError> {
+        let Some(&b) = self.text.get(self.pos) else {
+            return Err(self.error("unterminated string"));
+        };
+        self.pos += 1;
+        let radix_digits = |parser: &mut Self, radix: u32, max: usize, first: Option<u8>| {
+            let mut value = first.map_or(0, |b| (b - b'0') as u32);
+            let mut count = first.is_some() as usize;
+            while count < max {
+                let digit = parser.text.get(parser.pos).and_then(|&b| (b as char).to_digit(radix));
+                let Some(digit) = digit else {
+                    break;
+                };
+                value = value * radix + digit;
+                parser.pos += 1;
+                count += 1;
+            }
+            if count == 0 || value > 0xff {
+                return Err(parser.error("invalid escape"));
+            }
+            Ok(value as u8)
+        };
+        Ok(match b {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'v' => 0x0b,
+            b'"' | b'\'' | b'\\' | b'?' => b,
+            b'x' | b'X' => radix_digits(self, 16, 2, None)?,
+            b'0'..=b'7' => radix_digits(self, 8, 3, Some(b))?,
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    /// Parse fields until the end of the input, or until `close` for a nested
+    /// message.
+    fn fields(&mut self, close: Option<u8>) -> Result<Vec<(String, TextValue)>, TextError> {
+        let mut fields = Vec::new();
+        loop {
+            match self.peek() {
+                None if close.is_none() => return Ok(fields),
+                None => return Err(self.error("unexpected end of input")),
+                Some(b) if Some(b) == close => {
+                    self.pos += 1;
+                    return Ok(fields);
+                }
+                _ => (),
+            }
+            let name = self.token()?;
+            let colon = self.eat(b':');
+            match self.peek() {
+                Some(b'{' | b'<') => fields.push((name, self.value()?)),
+                _ if !colon => return Err(self.error("expected `:`")),
+                // A list is shorthand for repeating the field.
+                Some(b'[') => {
+                    self.pos += 1;
+                    if !self.eat(b']') {
+                        loop {
+                            fields.push((name.clone(), self.value()?));
+                            if self.eat(b']') {
+                                break;
+                            }
+                            if !self.eat(b',') {
+                                return Err(self.error("expected `,` or `]`"));
+                            }
+                        }
+                    }
+                }
+                _ => fields.push((name, self.value()?)),
+            }
+            // Fields may be separated by commas or semicolons.
+            if !self.eat(b',') {
+                self.eat(b';');
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<TextValue, TextError> {
+        match self.peek() {
+            Some(b'{') => self.message(b'}'),
+            Some(b'<') => self.message(b'>'),
+            Some(b'"' | b'\'') => Ok(TextValue::String(self.strings()?)),
+            _ => Ok(TextValue::Scalar(self.token()?)),
+        }
+    }
+
+    /// Parse a nested message, starting at the `{` or `<` that opens it.
+    fn message(&mut self, close: u8) -> Result<TextValue, TextError> {
+        if self.depth == MAX_TEXT_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        let fields = self.fields(Some(close))?;
+        self.depth -= 1;
+        Ok(TextValue::Message(fields))
+    }
+}
+
+/// Print a message in the protobuf text format.
+fn to_text_format<T: ProtoText>(message: &T) -> String {
+    let mut out = String::new();
+    write_text_fields(&message.to_text(), 0, &mut out);
+    out
+}
+
+/// Convert text format for a message of type `T` into wire data, which
+/// `parse_message::<T>` can then decode.
+fn text_to_wire<T: ProtoText>(text: &str) -> Result<Vec<u8>, TextError> {
+    let mut parser = TextParser { text: text.as_bytes(), pos: 0, line: 1, depth: 0 };
+    let fields = parser.fields(None)?;
+    let mut out = Vec::new();
+    T::text_to_wire(&fields, &mut out)?;
+    Ok(out)
+}
+
+/// An error reading length-delimited messages from a stream.
+#[derive(Debug)]
+enum StreamError {
+    /// Reading failed, or the stream ended partway through a message.
+    Io(std::io::Error),
+    /// A length prefix was larger than the stream's `max_total_bytes` limit.
+    MessageTooLarge { len: u64, max: usize },
+    /// The length prefix itself was malformed.
+    InvalidLength(DecodeError),
+    /// A message could not be decoded. `position` is the offset of the
+    /// message within the stream, and the error's offset is within the message.
+    Decode { position: u64, error: DecodeError },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(err) => write!(f, "{err}"),
+            StreamError::MessageTooLarge { len, max } => {
+                write!(f, "message of {len} bytes exceeds the maximum of {max}")
+            }
+            StreamError::InvalidLength(err) => write!(f, "invalid length prefix: {err}"),
+            StreamError::Decode { position, error } => {
+                write!(f, "message at byte {position}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<std::io::Error> for StreamError {
+    fn from(err: std::io::Error) -> Self {
+        StreamError::Io(err)
+    }
+}
+
+/// Reads a sequence of messages, each preceded by its length as a VARINT (the
+/// format written by `writeDelimitedTo` in other protobuf libraries).
+///
+/// Only one message is buffered at a time. The length prefix is read a byte at
+/// a time, so wrap unbuffered readers such as files in a `BufReader`.
+struct MessageStream<R: std::io::Read> {
+    reader: R,
+    buf: Vec<u8>,
+    options: DecodeOptions,
+    /// Number of bytes read from `reader` so far.
+    position: u64,
+}
+
+impl<R: std::io::Read> MessageStream<R> {
+    fn new(reader: R) -> Self {
+        MessageStream {
+            reader,
+            buf: Vec::new(),
+            options: DecodeOptions::default(),
+            position: 0,
+        }
+    }
+
+    /// Set the limits used when decoding messages from the stream. Length
+    /// prefixes over `max_total_bytes` are rejected before anything is
+    /// allocated for them.
+    fn decode_options(mut self, options: DecodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Read the length prefix of the next message, or `None` at the end of the
+    /// stream.
+    fn read_length(&mut self) -> Result<Option<u64>, StreamError> {
+        let mut prefix = [0u8; 10];
+        let mut len = 0;
+        loop {
+            let mut byte = [0u8];
+            let read = match self.reader.read(&mut byte) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                read => read?,
+            };
+            if read == 0 {
+                if len == 0 {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.position += 1;
+            prefix[len] = byte[0];
+            len += 1;
+            if byte[0] & 0x80 == 0 || len == prefix.len() {
+                break;
+            }
+        }
+        let (value, _) = parse_varint(&prefix[..len]).map_err(StreamError::InvalidLength)?;
+        Ok(Some(value))
+    }
+
+    /// Read the bytes of the next message, or `None` at the end of the stream.
+    /// The returned slice is only valid until the next read.
+    fn read_frame(&mut self) -> Result<Option<&[u8]>, StreamError> {
+        let Some(len) = self.read_length()? else {
+            return Ok(None);
+        };
+        let max = self.options.max_total_bytes;
+        if len > max as u64 {
+            return Err(StreamError::MessageTooLarge { len, max });
+        }
+        self.buf.resize(len as usize, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        self.position += len;
+        Ok(Some(&self.buf))
+    }
+
+    /// Read and decode the next message, or return `None` at the end of the
+    /// stream. The message borrows from the stream's buffer, so it must be
+    /// dropped before the next one is read.
+    fn read_message<'s, T: ProtoMessage<'s>>(&'s mut self) -> Result<Option<T>, StreamError> {
+        if self.read_frame()?.is_none() {
+            return Ok(None);
+        }
+        let position = self.position - self.buf.len() as u64;
+        parse_message_with(&self.buf, &self.options)
+            .map(Some)
+            .map_err(|error| StreamError::Decode { position, error })
+    }
+
+    /// Read and decode the next message like `read_message`, but return an
+    /// owned copy that can outlive the stream's buffer.
+    fn read_owned<'s, T>(&'s mut self) -> Result<Option<T::Owned>, StreamError>
+    where
+        T: ProtoMessage<'s> + IntoOwned,
+    {
+        Ok(self.read_message::<T>()?.map(T::into_owned))
+    }
+}
+
+/// Append a message to `out` preceded by its length, as read by `MessageStream`.
+fn encode_delimited<T: ProtoEncode>(message: &T, out: &mut Vec<u8>) {
+    let encoded = encode(message);
+    encode_varint(encoded.len() as u64, out);
+    out.extend_from_slice(&encoded);
+}
+
+/// A message decoded without a schema, keeping every field in wire order.
+#[derive(Debug, Default)]
+struct DynamicMessage<'a> {
+    fields: Vec<Field<'a>>,
+}
+
+impl<'a> ProtoMessage<'a> for DynamicMessage<'a> {
+    fn add_field(
+        &mut self,
+        field: Field<'a>,
+        _options: &DecodeOptions,
+        _depth: usize,
+    ) -> Result<(), DecodeError> {
+        self.fields.push(field);
+        Ok(())
+    }
+
+    fn merge_from(&mut self, other: Self) {
+        self.fields.extend(other.fields);
+    }
+}
+
+impl<'a> DynamicMessage<'a> {
+    /// The values of every occurrence of the given field number.
+    fn get(&self, field_num: u64) -> impl Iterator<Item = &FieldValue<'a>> {
+        self.fields
+            .iter()
+            .filter(move |field| field.field_num == field_num)
+            .map(|field| &field.value)
+    }
+
+    /// Write the fields in the style of `protoc --decode_raw`, indented by
+    /// `depth` levels. Nested messages past `options.max_depth` are written as
+    /// bytes.
+    fn write_fields(
+        &self,
+        f: &mut fmt::Formatter,
+        options: &DecodeOptions,
+        depth: usize,
+    ) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        for field in &self.fields {
+            let num = field.field_num;
+            match &field.value {
+                FieldValue::Varint(value) => writeln!(f, "{indent}{num}: {value}")?,
+                FieldValue::I64(value) => writeln!(f, "{indent}{num}: 0x{value:016x}")?,
+                FieldValue::I32(value) => writeln!(f, "{indent}{num}: 0x{value:08x}")?,
+                FieldValue::Len(data) => match guess_len_contents(data, options, depth) {
+                    LenContents::String(text) => writeln!(f, "{indent}{num}: {text:?}")?,
+                    LenContents::Message(message, nested) => {
+                        writeln!(f, "{indent}{num} {{")?;
+                        message.write_fields(f, options, nested)?;
+                        writeln!(f, "{indent}}}")?;
+                    }
+                    LenContents::Bytes => {
+                        write!(f, "{indent}{num}: \"")?;
+                        for &b in *data {
+                            match b {
+                                b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                                0x20..=0x7e => write!(f, "{}", b as char)?,
+                                _ => write!(f, "\\x{b:02x}")?,
+                            }
+                        }
+                        writeln!(f, "\"")?;
+                    }
+                },
+                FieldValue::Group(data) => {
+                    writeln!(f, "{indent}{num} {{")?;
+                    let group = options.nested(depth).and_then(|nested| {
+                        let mut group = DynamicMessage::default();
+                        merge_message_at(&mut group, data, options, nested)?;
+                        Ok((group, nested))
+                    });
+                    match group {
+                        Ok((group, nested)) => group.write_fields(f, options, nested)?,
+                        Err(err) => writeln!(f, "{indent}  # {err}")?,
+                    }
+                    writeln!(f, "{indent}}}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DynamicMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_fields(f, &DecodeOptions::default(), 0)
+    }
+}
+
+/// What the bytes of a `Len` field most likely hold, as the wire format
+/// doesn't say.
+enum LenContents<'a> {
+    String(&'a str),
+    /// A message, with the depth it is nested at.
+    Message(DynamicMessage<'a>, usize),
+    Bytes,
+}
+
+/// Guess what a `Len` field holds. Text without control characters is taken
+/// to be a string, since short strings often also happen to parse as a
+/// message. Otherwise anything that parses completely within `options` is a
+/// message.
+fn guess_len_contents<'a>(
+    data: &'a [u8],
+    options: &DecodeOptions,
+    depth: usize,
+) -> LenContents<'a> {
+    if let Ok(text) = std::str::from_utf8(data) {
+        if text.chars().all(|c| !c.is_control() || c == '\n' || c == '\t') {
+            return LenContents::String(text);
+        }
+    }
+    let Ok(nested) = options.nested(depth) else {
+        return LenContents::Bytes;
+    };
+    let mut message = DynamicMessage::default();
+    match merge_message_at(&mut message, data, options, nested) {
+        Ok(()) if !data.is_empty() => LenContents::Message(message, nested),
+        _ => LenContents::Bytes,
+    }
+}
+
+/// An error in a `.proto` schema, with the line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SchemaError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// A parsed `.proto` file.
+#[derive(Debug, Default, PartialEq)]
+struct ProtoFile {
+    package: Option<String>,
+    messages: Vec<MessageDef>,
+    enums: Vec<EnumDef>,
+}
+
+/// A `message` definition, including any types nested inside it.
+#[derive(Debug, Default, PartialEq)]
+struct MessageDef {
+    name: String,
+    line: usize,
+    fields: Vec<FieldDef>,
+    oneofs: Vec<OneofDef>,
+    messages: Vec<MessageDef>,
+    enums: Vec<EnumDef>,
+}
+
+/// A field of a message or oneof.
+#[derive(Debug, PartialEq)]
+struct FieldDef {
+    name: String,
+    number: u64,
+    label: FieldLabel,
+    type_: FieldType,
+    line: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldLabel {
+    Singular,
+    Optional,
+    Repeated,
+}
+
+#[derive(Debug, PartialEq)]
+enum FieldType {
+    /// A scalar type, or the name of a message or enum as written in the schema.
+    Named(String),
+    /// A `map<key, value>` field.
+    Map(String, String),
+}
+
+/// A `oneof` group. At most one of its fields is set at a time.
+#[derive(Debug, PartialEq)]
+struct OneofDef {
+    name: String,
+    line: usize,
+    fields: Vec<FieldDef>,
+}
+
+/// An `enum` definition and its values.
+#[derive(Debug, PartialEq)]
+struct EnumDef {
+    name: String,
+    line: usize,
+    values: Vec<(String, i32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SchemaToken {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Symbol(char),
+}
+
+/// Split a schema into tokens, each paired with its line number.
+fn tokenize_schema(source: &str) -> Result<Vec<(SchemaToken, usize)>, SchemaError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            prev = c;
+                        }
+                        None => {
+                            return Err(SchemaError {
+                                line,
+                                message: "unterminated comment".into(),
+                            })
+                        }
+                    }
+                }
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') => value.extend(chars.next()),
+                        Some('\n') | None => {
+                            return Err(SchemaError {
+                                line,
+                                message: "unterminated string".into(),
+                            })
+                        }
+                        Some(c) => value.push(c),
+                    }
+                }
+                tokens.push((SchemaToken::Str(value), line));
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && chars.peek().is_some_and(char::is_ascii_digit)) =>
+            {
+                let mut text = String::from(c);
+                while let Some(c) = chars.next_if(char::is_ascii_alphanumeric) {
+                    text.push(c);
+                }
+                let (negative, digits) = match text.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, text.as_str()),
+                };
+                let value = match digits
+                    .strip_prefix("0x")
+                    .or_else(|| digits.strip_prefix("0X"))
+                {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => digits.parse(),
+                }
+                .map_err(|_| SchemaError {
+                    line,
+                    message: format!("invalid number `{text}`"),
+                })?;
+                tokens.push((
+                    SchemaToken::Int(if negative { -value } else { value }),
+                    line,
+                ));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let mut ident = String::from(c);
+                while let Some(c) =
+                    chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                {
+                    ident.push(c);
+                }
+                tokens.push((SchemaToken::Ident(ident), line));
+            }
+            '{' | '}' | '[' | ']' | '(' | ')' | '<' | '>' | ';' | '=' | ',' => {
+                tokens.push((SchemaToken::Symbol(c), line));
+            }
+            _ => {
+                return Err(SchemaError {
+                    line,
+                    message: format!("unexpected character `{c}`"),
+                })
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive-descent parser over the tokens of a `.proto` file.
+struct SchemaParser {
+    tokens: Vec<(SchemaToken, usize)>,
+    pos: usize,
+}
+
+impl SchemaParser {
+    fn peek(&self) -> Option<&SchemaToken> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some((_, line)) => *line,
+            None => 1,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> SchemaError {
+        SchemaError {
+            line: self.line(),
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<SchemaToken, SchemaError> {
+        let Some((token, _)) = self.tokens.get(self.pos) else {
+            return Err(self.error("unexpected end of file"));
+        };
+        self.pos += 1;
+        Ok(token.clone())
+    }
+
+    fn eat_symbol(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&SchemaToken::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), SchemaError> {
+        if !self.eat_symbol(symbol) {
+            return Err(self.error(format!("expected `{symbol}`")));
+        }
+        Ok(())
+    }
+
+    fn expect_ident(&mut self) -> Result<String, SchemaError> {
+        match self.next()? {
+            SchemaToken::Ident(ident) => Ok(ident),
+            token => {
+                self.pos -= 1;
+                Err(self.error(format!("expected a name, found {token:?}")))
+            }
+        }
+    }
+
+    fn expect_int(&mut self) -> Result<i64, SchemaError> {
+        match self.next()? {
+            SchemaToken::Int(value) => Ok(value),
+            token => {
+                self.pos -= 1;
+                Err(self.error(format!("expected a number, found {token:?}")))
+            }
+        }
+    }
+
+    /// Skip to the end of a statement we don't need, such as `option` or
+    /// `reserved`, including any `{ ... }` block it contains.
+    fn skip_statement(&mut self) -> Result<(), SchemaError> {
+        let mut depth = 0;
+        loop {
+            match self.next()? {
+                SchemaToken::Symbol(';') if depth == 0 => return Ok(()),
+                SchemaToken::Symbol('{') => depth += 1,
+                SchemaToken::Symbol('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Skip a `[ ... ]` list of field options, if present.
+    fn skip_field_options(&mut self) -> Result<(), SchemaError> {
+        if self.eat_symbol('[') {
+            while self.next()? != SchemaToken::Symbol(']') {}
+        }
+        Ok(())
+    }
+
+    fn parse_file(&mut self) -> Result<ProtoFile, SchemaError> {
+        let mut file = ProtoFile::default();
+        while let Some(token) = self.peek() {
+            if *token == SchemaToken::Symbol(';') {
+                self.pos += 1;
+                continue;
+            }
+            match self.expect_ident()?.as_str() {
+                "syntax" => {
+                    self.expect_symbol('=')?;
+                    match self.next()? {
+                        SchemaToken::Str(syntax) if syntax == "proto3" => (),
+                        _ => return Err(self.error("only `syntax = \"proto3\"` is supported")),
+                    }
+                    self.expect_symbol(';')?;
+                }
+                "package" => {
+                    file.package = Some(self.expect_ident()?);
+                    self.expect_symbol(';')?;
+                }
+                "import" | "option" | "service" | "extend" => self.skip_statement()?,
+                "message" => file.messages.push(self.parse_message_def()?),
+                "enum" => file.enums.push(self.parse_enum_def()?),
+                other => return Err(self.error(format!("unexpected `{other}`"))),
+            }
+        }
+        Ok(file)
+    }
+
+    fn parse_message_def(&mut self) -> Result<MessageDef, SchemaError> {
+        let mut message = MessageDef {
+            line: self.line(),
+            name: self.expect_ident()?,
+            ..Default::default()
+        };
+        self.expect_symbol('{')?;
+        while !self.eat_symbol('}') {
+            if self.eat_symbol(';') {
+                continue;
+            }
+            let line = self.line();
+            match self.expect_ident()?.as_str() {
+                "message" => message.messages.push(self.parse_message_def()?),
+                "enum" => message.enums.push(self.parse_enum_def()?),
+                "option" | "reserved" | "extensions" | "extend" => self.skip_statement()?,
+                "oneof" => {
+                    let name = self.expect_ident()?;
+                    let mut fields = Vec::new();
+                    self.expect_symbol('{')?;
+                    while !self.eat_symbol('}') {
+                        let line = self.line();
+                        match self.expect_ident()?.as_str() {
+                            "option" => self.skip_statement()?,
+                            type_ => {
+                                let type_ = FieldType::Named(type_.to_string());
+                                fields.push(self.parse_field_def(
+                                    FieldLabel::Singular,
+                                    type_,
+                                    line,
+                                )?);
+                            }
+                        }
+                    }
+                    message.oneofs.push(OneofDef { name, line, fields });
+                }
+                "map" if self.eat_symbol('<') => {
+                    let key = self.expect_ident()?;
+                    self.expect_symbol(',')?;
+                    let value = self.expect_ident()?;
+                    self.expect_symbol('>')?;
+                    let type_ = FieldType::Map(key, value);
+                    message
+                        .fields
+                        .push(self.parse_field_def(FieldLabel::Repeated, type_, line)?);
+                }
+                label @ ("repeated" | "optional") => {
+                    let label = if label == "repeated" {
+                        FieldLabel::Repeated
+                    } else {
+                        FieldLabel::Optional
+                    };
+                    let type_ = FieldType::Named(self.expect_ident()?);
+                    message
+                        .fields
+                        .push(self.parse_field_def(label, type_, line)?);
+                }
+                "required" => return Err(self.error("`required` fields do not exist in proto3")),
+                type_ => {
+                    let type_ = FieldType::Named(type_.to_string());
+                    message
+                        .fields
+                        .push(self.parse_field_def(FieldLabel::Singular, type_, line)?);
+                }
+            }
+        }
+        Ok(message)
+    }
+
+    /// Parse the `name = number [options];` part of a field definition.
+    fn parse_field_def(
+        &mut self,
+        label: FieldLabel,
+        type_: FieldType,
+        line: usize,
+    ) -> Result<FieldDef, SchemaError> {
+        let name = self.expect_ident()?;
+        self.expect_symbol('=')?;
+        let number = self.expect_int()?;
+        if !(1..=0x1fff_ffff).contains(&number) {
+            return Err(self.error(format!("invalid field number {number}")));
+        }
+        self.skip_field_options()?;
+        self.expect_symbol(';')?;
+        Ok(FieldDef {
+            name,
+            number: number as u64,
+            label,
+            type_,
+            line,
+        })
+    }
+
+    fn parse_enum_def(&mut self) -> Result<EnumDef, SchemaError> {
+        let mut def = EnumDef {
+            line: self.line(),
+            name: self.expect_ident()?,
+            values: Vec::new(),
+        };
+        self.expect_symbol('{')?;
+        while !self.eat_symbol('}') {
+            if self.eat_symbol(';') {
+                continue;
+            }
+            match self.expect_ident()?.as_str() {
+                "option" | "reserved" => self.skip_statement()?,
+                name => {
+                    let name = name.to_string();
+                    self.expect_symbol('=')?;
+                    let value = self.expect_int()?;
+                    let value = i32::try_from(value).map_err(|_| {
+                        self.error(format!("enum value {value} does not fit in int32"))
+                    })?;
+                    self.skip_field_options()?;
+                    self.expect_symbol(';')?;
+                    def.values.push((name, value));
+                }
+            }
+        }
+        Ok(def)
+    }
+}
+
+/// Parse the text of a `proto3` schema.
+fn parse_schema(source: &str) -> Result<ProtoFile, SchemaError> {
+    let tokens = tokenize_schema(source)?;
+    SchemaParser { tokens, pos: 0 }.parse_file()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SchemaKind {
+    Message,
+    Enum,
+}
+
+/// Every message and enum in a schema, keyed by its full name relative to the
+/// package (for example `Person.PhoneNumber`).
+struct SchemaTypes<'s> {
+    package: Option<&'s str>,
+    kinds: std::collections::HashMap<String, SchemaKind>,
+}
+
+impl<'s> SchemaTypes<'s> {
+    fn new(file: &'s ProtoFile) -> Self {
+        fn collect(
+            scope: &str,
+            messages: &[MessageDef],
+            enums: &[EnumDef],
+            kinds: &mut std::collections::HashMap<String, SchemaKind>,
+        ) {
+            for def in enums {
+                kinds.insert(format!("{scope}{}", def.name), SchemaKind::Enum);
+            }
+            for def in messages {
+                let name = format!("{scope}{}", def.name);
+                kinds.insert(name.clone(), SchemaKind::Message);
+                collect(&format!("{name}."), &def.messages, &def.enums, kinds);
+            }
+        }
+
+        let mut kinds = Default::default();
+        collect("", &file.messages, &file.enums, &mut kinds);
+        SchemaTypes { package: file.package.as_deref(), kinds }
+    }
+
+    /// Resolve a type name used inside the message `scope`, following the
+    /// protobuf rule of searching the innermost scope first.
+    fn resolve(
+        &self,
+        scope: &str,
+        name: &str,
+        line: usize,
+    ) -> Result<(String, SchemaKind), SchemaError> {
+        let mut candidates = Vec::new();
+        if let Some(absolute) = name.strip_prefix('.') {
+            candidates.push(absolute.to_string());
+        } else {
+            let mut scope = scope;
+            loop {
+                candidates.push(if scope.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{scope}.{name}")
+                });
+                if scope.is_empty() {
+                    break;
+                }
+                scope = scope.rfind('.').map_or("", |i| &scope[..i]);
+            }
+        }
+        for candidate in candidates {
+            let candidate = match self.package {
+                Some(package) => candidate
+                    .strip_prefix(package)
+                    .and_then(|rest| rest.strip_prefix('.'))
+                    .map_or(candidate.clone(), str::to_string),
+                None => candidate,
+            };
+            if let Some(kind) = self.kinds.get(&candidate) {
+                return Ok((candidate, *kind));
+            }
+        }
+        Err(SchemaError {
+            line,
+            message: format!("unknown type `{name}`"),
+        })
+    }
+
+    fn type_borrows(&self, scope: &str, type_name: &str, line: usize) -> Result<bool, SchemaError> {
+        if matches!(type_name, "string" | "bytes") {
+            return Ok(true);
+        }
+        if scalar_rust_type(type_name).is_some() {
+            return Ok(false);
+        }
+        let (_, kind) = self.resolve(scope, type_name, line)?;
+        Ok(kind == SchemaKind::Message)
+    }
+
+    /// The Rust type used to store a single value of `type_name`, and whether
+    /// that type is a message.
+    fn rust_type(
+        &self,
+        scope: &str,
+        type_name: &str,
+        line: usize,
+    ) -> Result<(String, bool), SchemaError> {
+        if let Some(rust_type) = scalar_rust_type(type_name) {
+            return Ok((rust_type.to_string(), false));
+        }
+        let (name, kind) = self.resolve(scope, type_name, line)?;
+        Ok(match kind {
+            SchemaKind::Enum => (name.replace('.', ""), false),
+            SchemaKind::Message => (struct_name(&name), true),
+        })
+    }
+}
+
+/// The Rust struct for a message. Every message borrows from the input, if
+/// only to keep its unknown fields.
+fn struct_name(full_name: &str) -> String {
+    format!("{}<'a>", full_name.replace('.', ""))
+}
+
+/// The Rust type for a scalar protobuf type, or `None` for messages and enums.
+fn scalar_rust_type(type_name: &str) -> Option<&'static str> {
+    Some(match type_name {
+        "double" => "f64",
+        "float" => "f32",
+        "int32" => "i32",
+        "int64" => "i64",
+        "uint32" => "u32",
+        "uint64" => "u64",
+        "sint32" => "Sint32",
+        "sint64" => "Sint64",
+        "fixed32" => "Fixed32",
+        "fixed64" => "Fixed64",
+        "sfixed32" => "Sfixed32",
+        "sfixed64" => "Sfixed64",
+        "bool" => "bool",
+        "string" => "Cow<'a, str>",
+        "bytes" => "Cow<'a, [u8]>",
+        _ => return None,
+    })
+}
+
+/// Turn a `snake_case` or `SCREAMING_CASE` name into `CamelCase`.
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first)
+                .chain(chars.map(|c| c.to_ascii_lowercase()))
+                .collect::<String>()
+        })
+        .collect()
+}
+
+/// A field name that is safe to use in Rust, following the `type_` convention
+/// for keywords.
+fn rust_field_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else",
+        "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+        "move", "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while", "yield",
+    ];
+    if KEYWORDS.contains(&name) {
+        format!("{name}_")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Generate Rust source for the messages and enums in a schema.
+///
+/// Each message becomes a struct declared with `proto_message!`, so it decodes
+/// zero-copy through `Field` and `FieldValue` like `Person`. Nested types are
+/// named by joining their path, so `Person.PhoneNumber` becomes
+/// `PersonPhoneNumber`.
+fn generate_rust(file: &ProtoFile) -> Result<String, SchemaError> {
+    check_type_names(file)?;
+    let types = SchemaTypes::new(file);
+    let mut out = String::new();
+    for def in &file.enums {
+        generate_enum("", def, &mut out);
+    }
+    for def in &file.messages {
+        generate_message(&types, "", def, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Check that no two types would be generated with the same Rust name. Nested
+/// names are flattened, so `A.B` becomes `AB`, and the enum for a oneof `c`
+/// in `A` becomes `AC`.
+fn check_type_names(file: &ProtoFile) -> Result<(), SchemaError> {
+    fn claim(
+        names: &mut HashMap<String, String>,
+        full_name: String,
+        rust_name: String,
+        line: usize,
+    ) -> Result<(), SchemaError> {
+        use std::collections::hash_map::Entry;
+
+        match names.entry(rust_name) {
+            Entry::Occupied(entry) => Err(SchemaError {
+                line,
+                message: format!(
+                    "`{full_name}` and `{}` would both be generated as `{}`",
+                    entry.get(),
+                    entry.key()
+                ),
+            }),
+            Entry::Vacant(entry) => {
+                entry.insert(full_name);
+                Ok(())
+            }
+        }
+    }
+
+    fn visit(
+        scope: &str,
+        messages: &[MessageDef],
+        enums: &[EnumDef],
+        names: &mut HashMap<String, String>,
+    ) -> Result<(), SchemaError> {
+        for def in enums {
+            let full_name = format!("{scope}{}", def.name);
+            claim(names, full_name.clone(), full_name.replace('.', ""), def.line)?;
+        }
+        for def in messages {
+            let full_name = format!("{scope}{}", def.name);
+            let rust_name = full_name.replace('.', "");
+            claim(names, full_name.clone(), rust_name.clone(), def.line)?;
+            for oneof in &def.oneofs {
+                let oneof_name = format!("{full_name}.{}", oneof.name);
+                let enum_name = format!("{rust_name}{}", camel_case(&oneof.name));
+                claim(names, oneof_name, enum_name, oneof.line)?;
+            }
+            visit(&format!("{full_name}."), &def.messages, &def.enums, names)?;
+        }
+        Ok(())
+    }
+
+    visit("", &file.messages, &file.enums, &mut HashMap::new())
+}
+
+fn generate_enum(scope: &str, def: &EnumDef, out: &mut String) {
+    use std::fmt::Write;
+
+    let name = format!("{scope}{}", def.name).replace('.', "");
+    writeln!(out, "proto_enum! {{").unwrap();
+    writeln!(out, "    #[derive(Debug, Clone, Copy, PartialEq, Eq)]").unwrap();
+    writeln!(out, "    enum {name} {{").unwrap();
+    let mut seen = Vec::new();
+    for (value_name, value) in &def.values {
+        // Aliases (`allow_alias`) would decode to whichever came first.
+        if seen.contains(value) {
+            continue;
+        }
+        seen.push(*value);
+        writeln!(out, "        {} = {value},", camel_case(value_name)).unwrap();
+    }
+    writeln!(out, "    }}\n}}\n").unwrap();
+}
+
+fn generate_message(
+    types: &SchemaTypes,
+    scope: &str,
+    def: &MessageDef,
+    out: &mut String,
+) -> Result<(), SchemaError> {
+    let full_name = format!("{scope}{}", def.name);
+    let inner_scope = format!("{full_name}.");
+    for nested in &def.enums {
+        generate_enum(&inner_scope, nested, out);
+    }
+    for nested in &def.messages {
+        generate_message(types, &inner_scope, nested, out)?;
+    }
+
+    // Every struct keeps the fields it doesn't recognise in `unknown_fields`.
+    let reserved = def
+        .fields
+        .iter()
+        .map(|field| (&field.name, field.line))
+        .chain(def.oneofs.iter().map(|oneof| (&oneof.name, oneof.line)))
+        .find(|(name, _)| *name == "unknown_fields");
+    if let Some((_, line)) = reserved {
+        return Err(SchemaError {
+            line,
+            message: "`unknown_fields` is reserved for the fields a message doesn't know".into(),
+        });
+    }
+
+    let mut fields = Vec::new();
+    for field in &def.fields {
+        let rust_type = match &field.type_ {
+            FieldType::Map(key, value) => {
+                let (key_type, _) = types.rust_type(&full_name, key, field.line)?;
+                let (value_type, _) = types.rust_type(&full_name, value, field.line)?;
+                format!("BTreeMap<{key_type}, {value_type}>")
+            }
+            FieldType::Named(type_name) => {
+                let (rust_type, is_message) = types.rust_type(&full_name, type_name, field.line)?;
+                match field.label {
+                    FieldLabel::Repeated => format!("Vec<{rust_type}>"),
+                    // Singular messages have presence, and are boxed so that
+                    // messages can refer to themselves.
+                    _ if is_message => format!("Option<Box<{rust_type}>>"),
+                    FieldLabel::Optional => format!("Option<{rust_type}>"),
+                    FieldLabel::Singular => rust_type,
+                }
+            }
+        };
+        fields.push((field.number, rust_field_name(&field.name), rust_type));
+    }
+    let mut oneofs = Vec::new();
+    for oneof in &def.oneofs {
+        let enum_name = format!("{}{}", full_name.replace('.', ""), camel_case(&oneof.name));
+        let mut members = Vec::new();
+        let mut borrows = false;
+        for field in &oneof.fields {
+            let FieldType::Named(type_name) = &field.type_ else {
+                unreachable!("oneof fields cannot be maps");
+            };
+            let (rust_type, is_message) = types.rust_type(&full_name, type_name, field.line)?;
+            let rust_type = if is_message {
+                format!("Box<{rust_type}>")
+            } else {
+                rust_type
+            };
+            borrows |= types.type_borrows(&full_name, type_name, field.line)?;
+            members.push((field.number, camel_case(&field.name), rust_type));
+        }
+        let enum_name = if borrows {
+            format!("{enum_name}<'a>")
+        } else {
+            enum_name
+        };
+        write_oneof(&enum_name, &members, out);
+        oneofs.push((rust_field_name(&oneof.name), format!("Option<{enum_name}>")));
+    }
+    write_struct(&struct_name(&full_name), &fields, &oneofs, out);
+    Ok(())
+}
+
+/// Write a `proto_oneof!` enum with the given (tag, variant, type) members.
+fn write_oneof(name: &str, members: &[(u64, String, String)], out: &mut String) {
+    use std::fmt::Write;
+
+    writeln!(out, "proto_oneof! {{").unwrap();
+    writeln!(out, "    #[derive(Debug, PartialEq)]").unwrap();
+    writeln!(out, "    enum {name} {{").unwrap();
+    for (number, variant, rust_type) in members {
+        writeln!(out, "        #[proto(tag = {number})]").unwrap();
+        writeln!(out, "        {variant}({rust_type}),").unwrap();
+    }
+    writeln!(out, "    }}\n}}\n").unwrap();
+}
+
+/// Write a `proto_message!` struct with the given (tag, name, type) fields and
+/// (name, type) oneof fields, followed by a field keeping unknown fields.
+fn write_struct(
+    name: &str,
+    fields: &[(u64, String, String)],
+    oneofs: &[(String, String)],
+    out: &mut String,
+) {
+    use std::fmt::Write;
+
+    writeln!(out, "proto_message! {{").unwrap();
+    writeln!(out, "    #[derive(Debug, Default, PartialEq)]").unwrap();
+    writeln!(out, "    struct {name} {{").unwrap();
+    for (number, name, rust_type) in fields {
+        writeln!(out, "        #[proto(tag = {number})]").unwrap();
+        writeln!(out, "        {name}: {rust_type},").unwrap();
+    }
+    for (name, rust_type) in oneofs {
+        writeln!(out, "        #[proto(oneof)]").unwrap();
+        writeln!(out, "        {name}: {rust_type},").unwrap();
+    }
+    writeln!(out, "        #[proto(unknown)]").unwrap();
+    writeln!(out, "        unknown_fields: UnknownFields<'a>,").unwrap();
+    writeln!(out, "    }}\n}}\n").unwrap();
+}
+
+/// Print the Rust code generated for the `.proto` file at `path`.
+fn generate_from_file(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let source = std::fs::read_to_string(path)?;
+    let file = parse_schema(&source)?;
+    print!("{}", generate_rust(&file)?);
+    Ok(())
+}
+
+/// Parse hex text such as `0a 07 6d` or `0x0a, 0x07, 0x6d`.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let digits = word.strip_prefix("0x").unwrap_or(word);
+        if digits.len() % 2 != 0 {
+            return Err(format!("odd number of hex digits in `{word}`"));
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).map_err(|_| format!("invalid hex `{word}`"))?;
+            bytes.push(u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex `{word}`"))?);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Read a message from stdin, as binary or as hex text, and print its fields
+/// without needing a schema.
+fn decode_raw(hex: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+    if hex {
+        input = parse_hex(std::str::from_utf8(&input)?)?;
+    }
+    let message: DynamicMessage = parse_message(&input)?;
+    print!("{message}");
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["gen", path] => generate_from_file(path),
+        ["decode-raw"] => decode_raw(false),
+        ["decode-raw", "--hex"] => decode_raw(true),
+        _ => demo(),
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+/// Decode and re-encode a sample `Person`, printing what each step gives.
+fn demo() -> Result<(), Box<dyn std::error::Error>> {
+    let data = [
+        0x0a, 0x07, 0x6d, 0x61, 0x78, 0x77, 0x65, 0x6c, 0x6c, 0x10, 0x2a, 0x1a,
+        0x12, 0x0a, 0x0e, 0x2b, 0x31, 0x32, 0x30, 0x32, 0x2d, 0x35, 0x35, 0x35,
+        0x2d, 0x31, 0x32, 0x31, 0x32, 0x10, 0x01, 0x1a, 0x10, 0x0a, 0x0e, 0x2b,
+        0x31, 0x38, 0x30, 0x30, 0x2d, 0x38, 0x36, 0x37, 0x2d, 0x35, 0x33, 0x30,
+        0x38,
+    ];
+    let person: Person = parse_message(&data)?;
+    println!("{:#?}", person);
+    // Encoding the decoded person gives back the original bytes.
+    println!("Re-encoded unchanged: {}", encode(&person) == data);
+    // As does converting it to JSON and back.
+    let json = to_json(&person);
+    println!("{json}");
+    println!("JSON round trip unchanged: {}", json_to_wire::<Person>(&json)? == data);
+    // And the text format.
+    let text = to_text_format(&person);
+    print!("{text}");
+    println!("Text round trip unchanged: {}", text_to_wire::<Person>(&text)? == data);
+
+    // Fields this version of `Person` doesn't know about survive re-encoding.
+    let mut newer = data.to_vec();
+    encode_field(4, &FieldValue::Len(b"maxwell@example.com"), &mut newer);
+    let person: Person = parse_message(&newer)?;
+    println!("Unknown field kept: {}", encode(&person) == newer);
+
+    // Enum values from a newer schema are kept as numbers.
+    let phone: PhoneNumber = parse_message(&[0x10, 0x07])?;
+    println!("Phone type from a newer schema: {:?}", phone.type_);
+
+    // Wire data can also be produced directly from Rust values.
+    let ada = Person {
+        name: "ada".into(),
+        id: 7,
+        phone: vec![PhoneNumber {
+            number: "+44-20-7946-0000".into(),
+            type_: PhoneType::Work,
+            ..Default::default()
+        }],
+        labels: BTreeMap::from([("team".into(), "engines".into())]),
+        ..Default::default()
+    };
+    let bytes = encode(&ada);
+    println!("{bytes:02x?}");
+    println!("Decodes back to the same person: {}", parse_message::<Person>(&bytes)? == ada);
+
+    // Length-delimited messages can be read back one at a time from a stream,
+    // each kept after the next is read.
+    let mut log = Vec::new();
+    encode_delimited(&person, &mut log);
+    encode_delimited(&ada, &mut log);
+    let limits = DecodeOptions::default().max_total_bytes(1 << 10).max_repeated(100);
+    let mut stream = MessageStream::new(&log[..]).decode_options(limits);
+    let mut people = Vec::new();
+    while let Some(person) = stream.read_owned::<Person>()? {
+        people.push(person);
+    }
+    let names: Vec<&str> = people.iter().map(|person| &person.name[..]).collect();
+    println!("Read {names:?} from the stream");
+
+    // Decoding more wire data into a message merges it in, the same as merging
+    // in a message decoded separately.
+    let mut merged: Person = parse_message(&data)?;
+    merge_message(&mut merged, &bytes)?;
+    println!("Merged: {merged:?}");
+    let mut other: Person = parse_message(&data)?;
+    other.merge_from(parse_message(&bytes)?);
+    println!("Same as merge_from: {}", merged == other);
+
+    // Small negative numbers stay short with ZigZag encoding, and values that
+    // are usually large take a fixed width.
+    let reading = Reading {
+        sensor: Fixed32(17),
+        timestamp: Fixed64(1_700_000_000),
+        celsius: Sint32(-4),
+        drift: Sint64(-300),
+        low: Sfixed32(-40),
+        high: Sfixed64(125),
+        source: Some(Source::Device("greenhouse".into())),
+    };
+    let encoded = encode(&reading);
+    println!("{encoded:02x?}");
+    let decoded: Reading = parse_message(&encoded)?;
+    println!("Decodes back to the same reading: {}", decoded == reading);
+    // Without the schema, the temperature is just a VARINT.
+    let raw: DynamicMessage = parse_message(&encoded)?;
+    println!("Field 3 without a schema: {:?}", raw.get(3).collect::<Vec<_>>());
+
+    // Limits reject input nested or repeated more than the caller allows.
+    let shallow = DecodeOptions::default().max_depth(0);
+    match parse_message_with::<Person>(&data, &shallow) {
+        Ok(person) => println!("Decoded within a depth of 0: {person:?}"),
+        Err(err) => println!("Rejected with a depth of 0: {err}"),
+    }
+
+    // A truncated buffer is reported rather than panicking.
+    match parse_message::<Person>(&[0x0a, 0x07, 0x6d, 0x61]) {
+        Ok(person) => println!("Decoded a truncated buffer: {person:?}"),
+        Err(err) => println!("Rejected a truncated buffer: {err}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sample person from `demo`.
+    const PERSON: [u8; 49] = [
+        0x0a, 0x07, 0x6d, 0x61, 0x78, 0x77, 0x65, 0x6c, 0x6c, 0x10, 0x2a, 0x1a,
+        0x12, 0x0a, 0x0e, 0x2b, 0x31, 0x32, 0x30, 0x32, 0x2d, 0x35, 0x35, 0x35,
+        0x2d, 0x31, 0x32, 0x31, 0x32, 0x10, 0x01, 0x1a, 0x10, 0x0a, 0x0e, 0x2b,
+        0x31, 0x38, 0x30, 0x30, 0x2d, 0x38, 0x36, 0x37, 0x2d, 0x35, 0x33, 0x30,
+        0x38,
+    ];
+
+    proto_oneof! {
+        #[derive(Debug, PartialEq)]
+        enum Contact<'a> {
+            #[proto(tag = 3)]
+            Email(Cow<'a, str>),
+            #[proto(tag = 4)]
+            Phone(PhoneNumber<'a>),
+        }
+    }
+
+    proto_message! {
+        #[derive(Debug, Default, PartialEq)]
+        struct Node<'a> {
+            #[proto(tag = 1)]
+            child: Option<Box<Node<'a>>>,
+            #[proto(tag = 2)]
+            values: Vec<Sint32>,
+            #[proto(oneof)]
+            contact: Option<Contact<'a>>,
+        }
+    }
+
+    /// A xorshift generator for the property and fuzz-style tests below.
+    ///
+    /// These would be a `cargo fuzz` target and `proptest` properties, but
+    /// this example is a single file with no Cargo.toml to declare either in.
+    /// Instead `check_decode` is what the fuzz target would run, the round-trip
+    /// tests are the properties, and inputs come from fixed seeds. Failures
+    /// are repeatable, but nothing shrinks them to a minimal input.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        /// A string slice of up to `max` characters, including ones that need
+        /// escaping in JSON and the text format.
+        fn text(&mut self, max: usize) -> &'static str {
+            const TEXT: &str = "maxwell +1-555 \"quoted\" back\\slash\ttab\nline é 😀 \u{1} end";
+            let starts: Vec<usize> = TEXT.char_indices().map(|(i, _)| i).collect();
+            let start = self.below(starts.len());
+            let len = self.below(max + 1).min(starts.len() - start);
+            let end = starts.get(start + len).copied().unwrap_or(TEXT.len());
+            &TEXT[starts[start]..end]
+        }
+
+        fn person(&mut self) -> Person<'static> {
+            let id = match self.below(3) {
+                0 => 0,
+                1 => self.next() % 1000,
+                _ => self.next(),
+            };
+            Person {
+                name: self.text(12).into(),
+                id,
+                phone: (0..self.below(4))
+                    .map(|_| PhoneNumber {
+                        number: self.text(16).into(),
+                        type_: PhoneType::from(match self.below(2) {
+                            0 => self.below(3) as i32,
+                            _ => self.next() as i32,
+                        }),
+                        ..Default::default()
+                    })
+                    .collect(),
+                labels: (0..self.below(3))
+                    .map(|_| (self.text(4).into(), self.text(8).into()))
+                    .collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    /// A reader that is interrupted before returning each byte.
+    struct Interrupting<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl std::io::Read for Interrupting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(1);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    /// What a fuzz target would run for each input: decoding must either fail
+    /// with an error or produce a message that survives re-encoding.
+    fn check_decode(data: &[u8]) {
+        let mut rest = data;
+        while let Ok((field, remainder)) = parse_field(rest) {
+            assert_eq!(field.raw.len(), rest.len() - remainder.len());
+            rest = remainder;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        if let Ok(message) = parse_message::<DynamicMessage>(data) {
+            let _ = message.to_string();
+        }
+        if let Ok(person) = parse_message::<Person>(data) {
+            let encoded = encode(&person);
+            assert_eq!(parse_message::<Person>(&encoded).as_ref(), Ok(&person));
+        }
+        if let Ok(node) = parse_message::<Node>(data) {
+            let encoded = encode(&node);
+            assert_eq!(parse_message::<Node>(&encoded).as_ref(), Ok(&node));
+        }
+    }
+
+    #[test]
+    fn decode_sample() {
+        let person: Person = parse_message(&PERSON).unwrap();
+        assert_eq!(person.name, "maxwell");
+        assert_eq!(person.id, 42);
+        assert_eq!(person.phone.len(), 2);
+        assert_eq!(person.phone[0].number, "+1202-555-1212");
+        assert_eq!(person.phone[0].type_, PhoneType::Home);
+        assert_eq!(person.phone[1].type_, PhoneType::Mobile);
+        assert_eq!(encode(&person), PERSON);
+    }
+
+    #[test]
+    fn errors_carry_offsets() {
+        assert_eq!(
+            parse_message::<Person>(&[0x0a, 0x07, 0x6d, 0x61]).unwrap_err(),
+            DecodeError::LengthPastEnd { offset: 2, needed: 7, remaining: 2 }
+        );
+        // The id is a VARINT, not a string.
+        assert_eq!(
+            parse_message::<Person>(&[0x0a, 0x00, 0x12, 0x00]).unwrap_err(),
+            DecodeError::WireTypeMismatch {
+                offset: 4,
+                expected: WireType::Varint,
+                found: WireType::Len,
+            }
+        );
+        // Offsets inside nested messages are relative to the outer buffer.
+        assert_eq!(
+            parse_message::<Person>(&[0x10, 0x01, 0x1a, 0x02, 0x0a, 0x05]).unwrap_err(),
+            DecodeError::LengthPastEnd { offset: 6, needed: 5, remaining: 0 }
+        );
+        assert_eq!(
+            parse_message::<Person>(&[0x0f]).unwrap_err(),
+            DecodeError::UnknownWireType { offset: 0, wire_type: 7 }
+        );
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(parse_varint(&[0x96, 0x01]), Ok((150, &[][..])));
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(parse_varint(&max), Ok((u64::MAX, &[][..])));
+        assert!(parse_varint(&[0xff; 11]).is_err());
+        assert_eq!(parse_varint(&[0x80]), Err(DecodeError::TruncatedVarint { offset: 0 }));
+        for value in [0, 1, 127, 128, 300, u64::MAX] {
+            let mut out = Vec::new();
+            encode_varint(value, &mut out);
+            assert_eq!(parse_varint(&out), Ok((value, &[][..])));
+        }
+        for (value, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MIN, u64::MAX)] {
+            assert_eq!(zigzag_encode(value), encoded);
+            assert_eq!(zigzag_decode(encoded), value);
+        }
+    }
+
+    #[test]
+    fn scalar_accessors() {
+        // An int32 of -1 is sign-extended to ten bytes.
+        let minus_one = [0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let (field, _) = parse_field(&minus_one).unwrap();
+        assert_eq!(field.value.as_int32(), Ok(-1));
+        assert_eq!(field.value.as_i64(), Ok(-1));
+        assert_eq!(
+            field.value.as_u32(),
+            Err(DecodeError::OutOfRange { offset: 0, value: u64::MAX, target: "uint32" })
+        );
+        assert_eq!(FieldValue::Varint(3).as_sint32(), Ok(-2));
+        assert!(FieldValue::Varint(2).as_bool().is_err());
+        // Fixed-width values are little-endian.
+        let (field, _) = parse_field(&[0x0d, 0x01, 0x02, 0x03, 0x04]).unwrap();
+        assert_eq!(field.value.as_fixed32(), Ok(0x0403_0201));
+        assert_eq!(FieldValue::I32(-1).as_fixed32(), Ok(u32::MAX));
+        assert_eq!(FieldValue::I64(1.5f64.to_bits() as i64).as_f64(), Ok(1.5));
+    }
+
+    #[test]
+    fn zigzag_and_fixed_width_fields() {
+        let reading = Reading {
+            sensor: Fixed32(1),
+            celsius: Sint32(-1),
+            drift: Sint64(-2),
+            high: Sfixed64(-1),
+            source: Some(Source::Manual(true)),
+            ..Default::default()
+        };
+        let mut expected = vec![0x0d, 0x01, 0x00, 0x00, 0x00, 0x18, 0x01, 0x20, 0x03, 0x31];
+        expected.extend([0xff; 8]);
+        expected.extend([0x40, 0x01]);
+        assert_eq!(encode(&reading), expected);
+        assert_eq!(parse_message::<Reading>(&expected), Ok(reading));
+
+        let raw: DynamicMessage = parse_message(&expected).unwrap();
+        assert!(matches!(raw.get(6).collect::<Vec<_>>()[..], [FieldValue::I64(-1)]));
+    }
+
+    #[test]
+    fn last_value_wins() {
+        // Singular fields keep their last value, and repeated fields collect
+        // every value.
+        let data = [0x10, 0x01, 0x10, 0x2a, 0x1a, 0x00, 0x1a, 0x00];
+        let person: Person = parse_message(&data).unwrap();
+        assert_eq!(person.id, 42);
+        assert_eq!(person.phone.len(), 2);
+        // Fields holding their default value are left out.
+        assert_eq!(encode(&person), data[2..]);
+    }
+
+    #[test]
+    fn groups() {
+        // Field 1 is a group holding a nested group and a VARINT, then field 2
+        // follows it.
+        let data = [0x0b, 0x1b, 0x1c, 0x20, 0x01, 0x0c, 0x10, 0x05];
+        let message: DynamicMessage = parse_message(&data).unwrap();
+        let group: Vec<_> = message.get(1).collect();
+        assert!(matches!(group[..], [FieldValue::Group(body)] if *body == &data[1..5]));
+        assert!(matches!(message.get(2).collect::<Vec<_>>()[..], [FieldValue::Varint(5)]));
+        assert_eq!(
+            parse_message::<DynamicMessage>(&[0x0b, 0x14]).unwrap_err(),
+            DecodeError::UnexpectedEndGroup { offset: 1, field_num: 2 }
+        );
+        assert!(matches!(
+            parse_message::<DynamicMessage>(&[0x0b, 0x08, 0x01]),
+            Err(DecodeError::UnterminatedGroup { field_num: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn decode_raw_output() {
+        let hex = "0a 03 61 64 61 10 07 1a 04 0a 02 34 34 25 01 00 00 00 32 02 ff fe";
+        let data = parse_hex(hex).unwrap();
+        let message: DynamicMessage = parse_message(&data).unwrap();
+        assert_eq!(
+            message.to_string(),
+            "1: \"ada\"\n2: 7\n3 {\n  1: \"44\"\n}\n4: 0x00000001\n6: \"\\xff\\xfe\"\n"
+        );
+        assert_eq!(parse_hex("0x0a, 0x03"), Ok(vec![0x0a, 0x03]));
+        assert!(parse_hex("0a 7").is_err());
+    }
+
+    #[test]
+    fn packed_and_unpacked_repeated() {
+        let mut packed = Vec::new();
+        encode_field(2, &FieldValue::Len(&[0x01, 0x02, 0x03]), &mut packed);
+        let node: Node = parse_message(&packed).unwrap();
+        assert_eq!(node.values, [Sint32(-1), Sint32(1), Sint32(-2)]);
+        // Repeated scalars are written packed.
+        assert_eq!(encode(&node), packed);
+
+        let unpacked = [0x10, 0x01, 0x10, 0x02];
+        let node: Node = parse_message(&unpacked).unwrap();
+        assert_eq!(node.values, [Sint32(-1), Sint32(1)]);
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        let mut data = PERSON.to_vec();
+        encode_field(9, &FieldValue::I32(7), &mut data);
+        let person: Person = parse_message(&data).unwrap();
+        assert_eq!(person.unknown, UnknownFields(vec![Cow::Borrowed(&data[PERSON.len()..])]));
+        assert_eq!(encode(&person), data);
+    }
+
+    #[test]
+    fn owned_messages_outlive_the_buffer() {
+        let buffer = PERSON.to_vec();
+        let person: Person = parse_message(&buffer).unwrap();
+        assert!(matches!(person.name, Cow::Borrowed(_)));
+        let person: Person<'static> = person.into_owned();
+        drop(buffer);
+        let person = std::thread::spawn(move || person).join().unwrap();
+        assert_eq!(encode(&person), PERSON);
+    }
+
+    #[test]
+    fn enums_keep_unrecognized_values() {
+        let phone: PhoneNumber = parse_message(&[0x10, 0x02]).unwrap();
+        assert_eq!(phone.type_, PhoneType::Work);
+        let phone: PhoneNumber = parse_message(&[0x10, 0x63]).unwrap();
+        assert_eq!(phone.type_, PhoneType::Unrecognized(99));
+        assert_eq!(encode(&phone), [0x10, 0x63]);
+        assert_eq!(to_json(&phone), r#"{"type":99}"#);
+    }
+
+    #[test]
+    fn last_oneof_member_wins() {
+        let mut data = Vec::new();
+        encode_field(3, &FieldValue::Len(b"a@example.com"), &mut data);
+        encode_field(4, &FieldValue::Len(&[0x0a, 0x01, b'1']), &mut data);
+        encode_field(4, &FieldValue::Len(&[0x10, 0x01]), &mut data);
+        let node: Node = parse_message(&data).unwrap();
+        let Some(Contact::Phone(phone)) = &node.contact else {
+            panic!("expected a phone, got {node:?}");
+        };
+        // Repeats of the same message member are merged.
+        assert_eq!(phone.number, "1");
+        assert_eq!(phone.type_, PhoneType::Home);
+        assert_eq!(to_json(&node), r#"{"phone":{"number":"1","type":"HOME"}}"#);
+
+        encode_field(3, &FieldValue::Len(b"b@example.com"), &mut data);
+        let node: Node = parse_message(&data).unwrap();
+        assert_eq!(node.contact, Some(Contact::Email("b@example.com".into())));
+        assert_eq!(text_to_wire::<Node>(r#"email: "b@example.com""#), Ok(encode(&node)));
+    }
+
+    #[test]
+    fn last_map_entry_wins() {
+        let mut data = Vec::new();
+        encode_field(5, &FieldValue::Len(b"\x0a\x01k\x12\x01a"), &mut data);
+        encode_field(5, &FieldValue::Len(b"\x0a\x01k\x12\x01b"), &mut data);
+        encode_field(5, &FieldValue::Len(b"\x12\x01c"), &mut data);
+        let person: Person = parse_message(&data).unwrap();
+        let labels: Vec<(&str, &str)> =
+            person.labels.iter().map(|(key, value)| (&key[..], &value[..])).collect();
+        assert_eq!(labels, [("", "c"), ("k", "b")]);
+    }
+
+    #[test]
+    fn merge_from_merges_nested_messages() {
+        let phone = |number: &'static str, type_| PhoneNumber {
+            number: number.into(),
+            type_,
+            ..Default::default()
+        };
+        let mut node = Node {
+            child: Some(Box::new(Node { values: vec![Sint32(1)], ..Default::default() })),
+            values: vec![Sint32(2)],
+            contact: Some(Contact::Phone(phone("1", PhoneType::Mobile))),
+        };
+        let other = Node {
+            child: Some(Box::new(Node {
+                values: vec![Sint32(3)],
+                contact: Some(Contact::Email("a@example.com".into())),
+                ..Default::default()
+            })),
+            values: vec![Sint32(4)],
+            contact: Some(Contact::Phone(phone("", PhoneType::Home))),
+        };
+        let mut data = encode(&node);
+        data.extend(encode(&other));
+        node.merge_from(other);
+
+        let child = node.child.as_ref().unwrap();
+        assert_eq!(child.values, [Sint32(1), Sint32(3)]);
+        assert_eq!(child.contact, Some(Contact::Email("a@example.com".into())));
+        assert_eq!(node.values, [Sint32(2), Sint32(4)]);
+        assert_eq!(node.contact, Some(Contact::Phone(phone("1", PhoneType::Home))));
+        assert_eq!(parse_message::<Node>(&data).as_ref(), Ok(&node));
+
+        // A different oneof member replaces the current one.
+        node.merge_from(Node {
+            contact: Some(Contact::Email("b@example.com".into())),
+            ..Default::default()
+        });
+        assert_eq!(node.contact, Some(Contact::Email("b@example.com".into())));
+    }
+
+    #[test]
+    fn merge_from_matches_decoding_concatenated_messages() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let (mut person, other) = (rng.person(), rng.person());
+            let (first, second) = (encode(&person), encode(&other));
+            let data = [&first[..], &second[..]].concat();
+            let mut merged: Person = parse_message(&first).unwrap();
+            merge_message(&mut merged, &second).unwrap();
+            person.merge_from(other);
+            assert_eq!(parse_message::<Person>(&data).as_ref(), Ok(&person));
+            assert_eq!(merged, person);
+        }
+    }
+
+    #[test]
+    fn json_and_text_formats() {
+        let person: Person = parse_message(&PERSON).unwrap();
+        let json = to_json(&person);
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"name":"maxwell","id":"42","phone":["#,
+                r#"{"number":"+1202-555-1212","type":"HOME"},{"number":"+1800-867-5308"}]}"#
+            )
+        );
+        assert_eq!(json_to_wire::<Person>(&json), Ok(PERSON.to_vec()));
+        assert_eq!(
+            json_to_wire::<Person>(r#"{"nickname":"max"}"#),
+            Err(JsonError::UnknownField("nickname".into()))
+        );
+
+        let text = r#"
+            name: "maxwell" id: 42
+            phone { number: "+1202-555-1212" type: HOME }
+            phone < number: "+1800-867-5308" >
+        "#;
+        assert_eq!(text_to_wire::<Person>(text), Ok(PERSON.to_vec()));
+        assert_eq!(text_to_wire::<Person>(&to_text_format(&person)), Ok(PERSON.to_vec()));
+        assert_eq!(
+            text_to_wire::<Person>("id: -1"),
+            Err(TextError::InvalidValue { field: "id".into(), expected: "u64" })
+        );
+    }
+
+    #[test]
+    fn malformed_json() {
+        assert_eq!(
+            JsonValue::parse(r#""\ud83d\ude00""#),
+            Ok(JsonValue::String("\u{1f600}".into()))
+        );
+        // Unpaired surrogates and escapes with non-hex digits are rejected.
+        for json in [r#""\ud800\u0041""#, r#""\ud800\ue000""#, r#""\u+041""#] {
+            assert!(matches!(
+                JsonValue::parse(json),
+                Err(JsonError::Syntax { message: "invalid \\u escape", .. })
+            ));
+        }
+        assert!(matches!(
+            JsonValue::parse(&"[".repeat(1_000_000)),
+            Err(JsonError::Syntax { offset: 100, message: "nested too deeply" })
+        ));
+    }
+
+    #[test]
+    fn malformed_text_format() {
+        assert_eq!(
+            text_to_wire::<Person>("+"),
+            Err(TextError::Syntax { line: 1, message: "expected a name or value" })
+        );
+        assert_eq!(
+            text_to_wire::<Person>(&"a {".repeat(1_000_000)),
+            Err(TextError::Syntax { line: 1, message: "nested too deeply" })
+        );
+    }
+
+    #[test]
+    fn stream() {
+        let mut data = Vec::new();
+        let people: Vec<Person> = (0..3u64).map(|id| Person { id, ..Default::default() }).collect();
+        for person in &people {
+            encode_delimited(person, &mut data);
+        }
+        let mut stream = MessageStream::new(&data[..]);
+        for person in &people {
+            assert_eq!(stream.read_message::<Person>().unwrap().as_ref(), Some(person));
+        }
+        assert!(stream.read_message::<Person>().unwrap().is_none());
+
+        // Owned messages can be kept while later ones are read, and reads are
+        // retried when interrupted.
+        let mut stream = MessageStream::new(Interrupting { data: &data, interrupt: false });
+        let mut read = Vec::new();
+        while let Some(person) = stream.read_owned::<Person>().unwrap() {
+            read.push(person);
+        }
+        assert_eq!(read, people);
+
+        let small = DecodeOptions::default().max_total_bytes(4);
+        let mut stream = MessageStream::new(&[0x05, 0x00][..]).decode_options(small);
+        assert!(matches!(
+            stream.read_message::<Person>(),
+            Err(StreamError::MessageTooLarge { len: 5, max: 4 })
+        ));
+
+        // Messages are decoded within the stream's limits.
+        let mut data = Vec::new();
+        encode_delimited(&parse_message::<Person>(&PERSON).unwrap(), &mut data);
+        let shallow = DecodeOptions::default().max_depth(0);
+        let mut stream = MessageStream::new(&data[..]).decode_options(shallow);
+        assert!(matches!(
+            stream.read_owned::<Person>(),
+            Err(StreamError::Decode {
+                position: 1,
+                error: DecodeError::LimitExceeded { limit: DecodeLimit::Depth(0), .. },
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_limits() {
+        let mut data = Vec::new();
+        for _ in 0..200 {
+            let mut outer = Vec::new();
+            encode_field(1, &FieldValue::Len(&data), &mut outer);
+            data = outer;
+        }
+        assert!(matches!(
+            parse_message::<Node>(&data),
+            Err(DecodeError::LimitExceeded { limit: DecodeLimit::Depth(100), .. })
+        ));
+        let deep = DecodeOptions::default().max_depth(200);
+        assert!(parse_message_with::<Node>(&data, &deep).is_ok());
+
+        let few = DecodeOptions::default().max_repeated(2);
+        let values = [0x12, 0x03, 0x01, 0x02, 0x03];
+        assert_eq!(
+            parse_message_with::<Node>(&values, &few),
+            Err(DecodeError::LimitExceeded { offset: 2, limit: DecodeLimit::RepeatedCount(2) })
+        );
+
+        let small = DecodeOptions::default().max_total_bytes(10);
+        assert!(matches!(
+            parse_message_with::<Person>(&PERSON, &small),
+            Err(DecodeError::LimitExceeded { limit: DecodeLimit::TotalBytes(10), .. })
+        ));
+
+        // Groups nested far deeper than the stack could recurse are skipped.
+        let mut groups = vec![0x0b; 100_000];
+        groups.extend(vec![0x0c; 100_000]);
+        assert!(parse_message::<DynamicMessage>(&groups).is_ok());
+    }
+
+    /// Expands to the given items, and to `CODE` holding them as text.
+    macro_rules! with_source {
+        ($($item:tt)*) => {
+            $($item)*
+            const CODE: &str = stringify!($($item)*);
+        };
+    }
+
+    /// What `generate_rust` should produce for `SCHEMA`. Declaring it here
+    /// checks that the generated code compiles.
+    mod generated {
+        use super::*;
+
+        const SCHEMA: &str = r#"
+            syntax = "proto3";
+            message Contact {
+                enum Kind { KIND_UNSPECIFIED = 0; KIND_HOME = 1; }
+                Kind kind = 1;
+                map<string, int64> counts = 2;
+                oneof via { string email = 3; Contact referrer = 4; }
+            }
+            message Empty {}
+        "#;
+
+        with_source! {
+            proto_enum! {
+                #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+                enum ContactKind {
+                    KindUnspecified = 0,
+                    KindHome = 1,
+                }
+            }
+
+            proto_oneof! {
+                #[derive(Debug, PartialEq)]
+                enum ContactVia<'a> {
+                    #[proto(tag = 3)]
+                    Email(Cow<'a, str>),
+                    #[proto(tag = 4)]
+                    Referrer(Box<Contact<'a>>),
+                }
+            }
+
+            proto_message! {
+                #[derive(Debug, Default, PartialEq)]
+                struct Contact<'a> {
+                    #[proto(tag = 1)]
+                    kind: ContactKind,
+                    #[proto(tag = 2)]
+                    counts: BTreeMap<Cow<'a, str>, i64>,
+                    #[proto(oneof)]
+                    via: Option<ContactVia<'a>>,
+                    #[proto(unknown)]
+                    unknown_fields: UnknownFields<'a>,
+                }
+            }
+
+            proto_message! {
+                #[derive(Debug, Default, PartialEq)]
+                struct Empty<'a> {
+                    #[proto(unknown)]
+                    unknown_fields: UnknownFields<'a>,
+                }
+            }
+        }
+
+        #[test]
+        fn generate_from_schema() {
+            let code = generate_rust(&parse_schema(SCHEMA).unwrap()).unwrap();
+            let tokens = |code: &str| code.split_whitespace().collect::<String>();
+            assert_eq!(tokens(&code), tokens(CODE));
+
+            let contact = Contact {
+                kind: ContactKind::KindHome,
+                counts: BTreeMap::from([("visits".into(), 3)]),
+                via: Some(ContactVia::Referrer(Box::default())),
+                ..Default::default()
+            };
+            let encoded = encode(&contact);
+            assert_eq!(parse_message::<Contact>(&encoded).as_ref(), Ok(&contact));
+            // Every field is unknown to an empty message, and kept.
+            let empty: Empty = parse_message(&encoded).unwrap();
+            assert_eq!(encode(&empty), encoded);
+            assert_eq!(
+                parse_schema("message A { string a = 1; Missing b = 2; }")
+                    .and_then(|file| generate_rust(&file)),
+                Err(SchemaError { line: 1, message: "unknown type `Missing`".into() })
+            );
+        }
+    }
+
+    #[test]
+    fn generated_names_must_not_clash() {
+        let generate = |schema| parse_schema(schema).and_then(|file| generate_rust(&file));
+        let error = |line, message: &str| Err(SchemaError { line, message: message.into() });
+        assert_eq!(
+            generate("message A {\n  string unknown_fields = 1;\n}"),
+            error(2, "`unknown_fields` is reserved for the fields a message doesn't know")
+        );
+        assert_eq!(
+            generate("message A {\n  oneof unknown_fields { string b = 1; }\n}"),
+            error(2, "`unknown_fields` is reserved for the fields a message doesn't know")
+        );
+        assert_eq!(
+            generate("message A { message B {} }\nmessage AB {}"),
+            error(2, "`AB` and `A.B` would both be generated as `AB`")
+        );
+        assert_eq!(
+            generate("message A { oneof b { string c = 1; } }\nenum AB { X = 0; }"),
+            error(1, "`A.b` and `AB` would both be generated as `AB`")
+        );
+    }
+
+    #[test]
+    fn round_trip_random_people() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let person = rng.person();
+            let encoded = encode(&person);
+            assert_eq!(parse_message::<Person>(&encoded).as_ref(), Ok(&person));
+
+            let json = json_to_wire::<Person>(&to_json(&person)).unwrap();
+            assert_eq!(parse_message::<Person>(&json).as_ref(), Ok(&person));
+
+            let text = text_to_wire::<Person>(&to_text_format(&person)).unwrap();
+            assert_eq!(parse_message::<Person>(&text).as_ref(), Ok(&person));
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let data: Vec<u8> = (0..rng.below(48)).map(|_| rng.next() as u8).collect();
+            check_decode(&data);
+        }
+    }
+
+    #[test]
+    fn fuzz_mutated_messages() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..5000 {
+            let mut data = encode(&rng.person());
+            for _ in 0..=rng.below(3) {
+                if data.is_empty() {
+                    break;
+                }
+                let i = rng.below(data.len());
+                match rng.below(4) {
+                    0 => data[i] = rng.next() as u8,
+                    1 => data[i] ^= 1 << rng.below(8),
+                    2 => data.truncate(i),
+                    _ => data.insert(i, rng.next() as u8),
+                }
+            }
+            check_decode(&data);
+        }
+    }
 }